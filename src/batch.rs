@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use globset::GlobBuilder;
+use walkdir::WalkDir;
+
+use whisper_cli::{TranscribeOptions, Transcript, Whisper};
+
+use crate::progress;
+
+/// Files picked up when walking a directory or matching a pattern.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aiff", "flac", "m4a", "mp3", "mp4", "mpeg", "mpga", "oga", "ogg", "opus", "wav",
+    "webm", "wma",
+];
+
+/// How a file of the batch went.
+pub enum Outcome {
+    Done {
+        /// Length of the audio.
+        audio: Duration,
+        elapsed: Duration,
+    },
+    /// Its outputs existed already.
+    Skipped,
+    Failed(String),
+}
+
+/// Expands the `inputs` given on the command line into the audio files they name:
+/// files as they are, directories recursively, and glob patterns like `calls/**/*.wav`.
+/// `-` stands for standard input. Each file is listed once, in the order it was first named.
+pub fn collect_inputs(inputs: &[String]) -> Result<Vec<PathBuf>> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        let found = if input == "-" || path.is_file() {
+            vec![path.to_path_buf()]
+        } else if path.is_dir() {
+            walk(path, |_| true)
+        } else if input.contains(['*', '?', '[', '{']) {
+            // `*` stays within a directory, `**` crosses them
+            let pattern = GlobBuilder::new(input)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("invalid pattern `{input}`"))?
+                .compile_matcher();
+            walk(&glob_root(path), |file| pattern.is_match(file))
+        } else {
+            return Err(anyhow!("{input} does not exist"));
+        };
+
+        if found.is_empty() {
+            return Err(anyhow!("no audio files found in {input}"));
+        }
+        files.extend(found.into_iter().filter(|file| seen.insert(file.clone())));
+    }
+
+    Ok(files)
+}
+
+/// Audio files below `dir` accepted by `filter`, sorted by path.
+fn walk(dir: &Path, filter: impl Fn(&Path) -> bool) -> Vec<PathBuf> {
+    let mut files: Vec<_> = WalkDir::new(dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .map(walkdir::DirEntry::into_path)
+        .filter(|path| is_audio(path) && filter(path))
+        .collect();
+    files.sort();
+    files
+}
+
+/// The leading components of `pattern` without wildcards, where matching files are searched.
+fn glob_root(pattern: &Path) -> PathBuf {
+    let root: PathBuf = pattern
+        .components()
+        .take_while(|component| {
+            !matches!(component, Component::Normal(name)
+                if name.to_string_lossy().contains(['*', '?', '[', '{']))
+        })
+        .collect();
+    if root.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        root
+    }
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Transcribes `files` with one model spread over `workers` decoding states, at least one.
+///
+/// Files for which `skip` returns true are left out. `finished` receives every transcript
+/// as soon as it is done, from the worker that made it, and may fail it. Returns the
+/// outcome of every file, in the order of `files`.
+pub fn transcribe_all<S, F>(
+    whisper: &Whisper,
+    files: &[PathBuf],
+    options: &TranscribeOptions,
+    workers: usize,
+    skip: S,
+    finished: F,
+) -> Vec<Outcome>
+where
+    S: Fn(&Path) -> bool + Sync,
+    F: Fn(&Path, &Transcript) -> Result<()> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let outcomes: Vec<Mutex<Option<Outcome>>> = files.iter().map(|_| Mutex::new(None)).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..workers.min(files.len()) {
+            scope.spawn(|| {
+                let mut state = match whisper.create_state() {
+                    Ok(state) => Some(state),
+                    Err(e) => {
+                        tracing::error!("failed to start a worker: {e:#}");
+                        None
+                    }
+                };
+
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = files.get(index) else {
+                        return;
+                    };
+
+                    let outcome = if skip(file) {
+                        Outcome::Skipped
+                    } else if let Some(state) = &mut state {
+                        let started = Instant::now();
+                        let span = tracing::info_span!("transcribe", audio = %file.display());
+                        span.in_scope(|| {
+                            let samples = Whisper::decode(file)?;
+                            let (_bar, progress) = progress::start(file, &samples);
+                            whisper
+                                .transcribe_decoded_with_state(state, &samples, options, progress)
+                        })
+                        .and_then(|transcript| {
+                            finished(file, &transcript)?;
+                            Ok(transcript)
+                        })
+                        .map_or_else(
+                            |e| Outcome::Failed(format!("{e:#}")),
+                            |transcript| Outcome::Done {
+                                audio: transcript.duration,
+                                elapsed: started.elapsed(),
+                            },
+                        )
+                    } else {
+                        Outcome::Failed("no decoding state".to_string())
+                    };
+                    if let Outcome::Failed(e) = &outcome {
+                        tracing::warn!(file = %file.display(), "failed: {e}");
+                    }
+                    *outcomes[index].lock().unwrap() = Some(outcome);
+                }
+            });
+        }
+    });
+
+    outcomes
+        .into_iter()
+        .map(|outcome| {
+            outcome
+                .into_inner()
+                .unwrap()
+                .unwrap_or_else(|| Outcome::Failed("not transcribed".to_string()))
+        })
+        .collect()
+}
+
+/// Prints a table of the outcomes to stderr, followed by their totals.
+pub fn print_summary(files: &[PathBuf], outcomes: &[Outcome], elapsed: Duration) {
+    let names: Vec<_> = files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect();
+    let width = names.iter().map(String::len).max().unwrap_or(0).max(4);
+
+    eprintln!(
+        "{:width$}  {:8}  {:>9}  {:>9}",
+        "FILE", "STATUS", "AUDIO", "TIME"
+    );
+    let (mut done, mut skipped, mut failed) = (0, 0, 0);
+    for (name, outcome) in names.iter().zip(outcomes) {
+        match outcome {
+            Outcome::Done { audio, elapsed } => {
+                done += 1;
+                eprintln!(
+                    "{name:width$}  {:8}  {:>8.1}s  {:>8.1}s",
+                    "done",
+                    audio.as_secs_f64(),
+                    elapsed.as_secs_f64()
+                );
+            }
+            Outcome::Skipped => {
+                skipped += 1;
+                eprintln!("{name:width$}  skipped");
+            }
+            Outcome::Failed(e) => {
+                failed += 1;
+                eprintln!("{name:width$}  failed    {e}");
+            }
+        }
+    }
+
+    eprintln!(
+        "\n{} file(s): {done} transcribed, {skipped} skipped, {failed} failed in {:.1}s",
+        files.len(),
+        elapsed.as_secs_f64()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_collect_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("calls/monday")).unwrap();
+        for file in [
+            "a.wav",
+            "notes.txt",
+            "calls/b.MP3",
+            "calls/d.wav",
+            "calls/monday/c.wav",
+        ] {
+            fs::write(root.join(file), b"").unwrap();
+        }
+        let input = |path: &str| root.join(path).display().to_string();
+
+        let files =
+            collect_inputs(&[input("calls"), input("a.wav"), input("calls/b.MP3")]).unwrap();
+        assert_eq!(
+            files,
+            [
+                root.join("calls/b.MP3"),
+                root.join("calls/d.wav"),
+                root.join("calls/monday/c.wav"),
+                root.join("a.wav")
+            ]
+        );
+
+        let files = collect_inputs(&[input("**/*.wav")]).unwrap();
+        assert_eq!(
+            files,
+            [
+                root.join("a.wav"),
+                root.join("calls/d.wav"),
+                root.join("calls/monday/c.wav")
+            ]
+        );
+
+        let files = collect_inputs(&[input("calls/*.wav")]).unwrap();
+        assert_eq!(files, [root.join("calls/d.wav")]);
+
+        assert!(collect_inputs(&[input("missing.wav")]).is_err());
+        assert!(collect_inputs(&[input("*.flac")]).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use dirs::cache_dir;
+
+use crate::{Language, TranscribeOptions, Transcript};
+
+/// Bumped whenever the key or the stored format changes, orphaning older entries.
+const VERSION: u32 = 1;
+
+/// On-disk store of finished transcripts, keyed by a hash of the decoded audio, the
+/// model and the decoding options.
+///
+/// Entries unused for longer than `max_age` are dropped, and beyond `max_size` bytes
+/// the least recently used ones go first. Reading an entry counts as using it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TranscriptCache {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+}
+
+impl Default for TranscriptCache {
+    fn default() -> Self {
+        Self::new(
+            Self::default_dir(),
+            Self::DEFAULT_MAX_SIZE,
+            Self::DEFAULT_MAX_AGE,
+        )
+    }
+}
+
+impl TranscriptCache {
+    pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64, max_age: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+            max_age,
+        }
+    }
+
+    /// The cache next to the downloaded models, shared by the CLI and the server.
+    pub fn default_dir() -> PathBuf {
+        let mut path = cache_dir().expect("Could not find cache directory");
+        path.push("whisper");
+        path.push("transcripts");
+
+        path
+    }
+
+    /// Identifies the transcription of `samples` by `model` with `options`.
+    pub fn key(
+        samples: &[f32],
+        model: &str,
+        language: Option<Language>,
+        options: &TranscribeOptions,
+    ) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&VERSION.to_le_bytes());
+        for sample in samples {
+            hasher.update(&sample.to_le_bytes());
+        }
+        // separators keep e.g. model `ab` + prompt `c` apart from model `a` + prompt `bc`
+        let settings = format!(
+            "{model}\0{language:?}\0{}\0{}\0{:?}\0{:?}",
+            options.translate,
+            options.word_timestamps,
+            options.prompt,
+            options.temperature.map(f32::to_bits),
+        );
+        hasher.update(settings.as_bytes());
+
+        hasher.finalize().to_hex().to_string()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// The stored transcript for `key`, if there is a fresh one.
+    pub fn get(&self, key: &str) -> Option<Transcript> {
+        let path = self.path(key);
+        let file = File::open(&path).ok()?;
+        if is_expired(&file, self.max_age) {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+
+        let transcript = serde_json::from_reader(BufReader::new(&file)).ok();
+        if transcript.is_none() {
+            let _ = fs::remove_file(&path);
+        } else {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        transcript
+    }
+
+    /// Stores `transcript` under `key`, then evicts entries beyond the limits.
+    pub fn put(&self, key: &str, transcript: &Transcript) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+
+        // written aside and moved in place, so readers never see half an entry
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        let mut writer = BufWriter::new(file.as_file_mut());
+        serde_json::to_writer(&mut writer, transcript)?;
+        writer.flush()?;
+        drop(writer);
+        file.persist(self.path(key))?;
+
+        self.evict()
+    }
+
+    /// Removes expired entries, then the least recently used ones until the cache fits
+    /// in `max_size`.
+    pub fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            let used = metadata.modified()?;
+            if used.elapsed().unwrap_or_default() > self.max_age {
+                let _ = fs::remove_file(&path);
+            } else {
+                entries.push((used, metadata.len(), path));
+            }
+        }
+
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort_unstable_by_key(|(used, _, _)| *used);
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                size -= len;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_expired(file: &File, max_age: Duration) -> bool {
+    file.metadata()
+        .and_then(|m| m.modified())
+        .map_or(true, |used| used.elapsed().unwrap_or_default() > max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript(text: &str) -> Transcript {
+        Transcript {
+            processing_time: Duration::from_secs(1),
+            duration: Duration::from_secs(2),
+            language: Some("en".to_string()),
+            utterances: vec![crate::Utternace {
+                start: 0,
+                stop: 200,
+                text: text.to_string(),
+                tokens: vec![1, 2],
+                avg_logprob: -0.5,
+            }],
+            word_utterances: None,
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let samples = [0.0, 0.5, -0.5];
+        let options = TranscribeOptions::default();
+        let key = TranscriptCache::key(&samples, "base.bin", None, &options);
+        let prompted = TranscribeOptions {
+            prompt: Some("Whisper".to_string()),
+            ..TranscribeOptions::default()
+        };
+        assert_ne!(
+            key,
+            TranscriptCache::key(&samples, "base.bin", None, &prompted)
+        );
+        assert_ne!(
+            key,
+            TranscriptCache::key(&samples, "base.bin", Some(Language::German), &options)
+        );
+        assert_ne!(
+            key,
+            TranscriptCache::key(&samples[1..], "base.bin", None, &options)
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TranscriptCache::new(dir.path(), 1024 * 1024, TranscriptCache::DEFAULT_MAX_AGE);
+        assert!(cache.get(&key).is_none());
+        cache.put(&key, &transcript("hello")).unwrap();
+        assert_eq!(
+            cache.get(&key).unwrap().as_text(),
+            transcript("hello").as_text()
+        );
+
+        // room for one entry, the least recently used one goes
+        let entry = fs::metadata(cache.path(&key)).unwrap().len();
+        let small = TranscriptCache::new(dir.path(), entry + 1, TranscriptCache::DEFAULT_MAX_AGE);
+        small.put("other", &transcript("bye")).unwrap();
+        assert!(small.get(&key).is_none());
+        assert!(small.get("other").is_some());
+
+        let expired = TranscriptCache::new(dir.path(), 1024 * 1024, Duration::ZERO);
+        assert!(expired.get("other").is_none());
+    }
+}
@@ -3,6 +3,7 @@ use audrey::Reader;
 use std::env::temp_dir;
 use std::path::Path;
 use std::process::Stdio;
+use std::sync::OnceLock;
 use std::{fs::File, process::Command};
 
 // ffmpeg -i input.mp3 -ar 16000 output.wav
@@ -27,7 +28,12 @@ fn use_ffmpeg<P: AsRef<Path>>(input_path: P) -> Result<Vec<i16>> {
             "-loglevel",
             "error",
         ])
-        .stdin(Stdio::null())
+        .stdin(if is_stdin(&input_path) {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
         .spawn()?;
 
     if pid.wait()?.success() {
@@ -41,31 +47,47 @@ fn use_ffmpeg<P: AsRef<Path>>(input_path: P) -> Result<Vec<i16>> {
     }
 }
 
+/// Decodes an audio file into 16 kHz mono samples. The path `-` reads standard input.
 pub fn read_file<P: AsRef<Path>>(audio_file_path: P) -> Result<Vec<f32>> {
     if is_ffmpeg_available() {
-        println!("ffmpeg is available");
+        tracing::debug!("decoding with ffmpeg");
         let audio_buf = use_ffmpeg(&audio_file_path)?;
         Ok(whisper_rs::convert_integer_to_float_audio(&audio_buf))
     } else {
-        println!("ffmpeg not found. Using hound decoder as fallback");
-        let mut reader = hound::WavReader::open(audio_file_path)?;
+        tracing::debug!("ffmpeg not found, decoding with hound");
+        if is_stdin(&audio_file_path) {
+            read_wav(hound::WavReader::new(std::io::stdin().lock())?)
+        } else {
+            read_wav(hound::WavReader::open(audio_file_path)?)
+        }
+    }
+}
 
-        // Convert i16 samples to f32 and normalize to [-1.0, 1.0]
-        let samples: Vec<f32> = reader
-            .samples::<i16>()
-            .map(|s| s.unwrap() as f32 / std::i16::MAX as f32)
-            .collect();
+fn read_wav<R: std::io::Read>(mut reader: hound::WavReader<R>) -> Result<Vec<f32>> {
+    // Convert i16 samples to f32 and normalize to [-1.0, 1.0]
+    let samples = reader
+        .samples::<i16>()
+        .map(|s| s.map(|s| s as f32 / i16::MAX as f32))
+        .collect::<Result<Vec<f32>, _>>()?;
 
-        Ok(samples)
-    }
+    Ok(samples)
 }
 
+fn is_stdin<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref() == Path::new("-")
+}
+
+/// Whether ffmpeg can be run, probed once. Its banner must not reach stdout, which
+/// may hold a transcript.
 fn is_ffmpeg_available() -> bool {
-    match std::process::Command::new("ffmpeg")
-        .arg("-version")
-        .status()
-    {
-        Ok(status) => status.success(),
-        Err(_) => false,
-    }
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        Command::new("ffmpeg")
+            .arg("-version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    })
 }
@@ -2,11 +2,16 @@ use std::path::Path;
 
 use anyhow::Result;
 
+pub use cache::TranscriptCache;
+pub use live::{LiveTranscriber, LiveUpdate};
 pub use model::{Model, Size};
 pub use transcript::{Transcript, Utternace};
-pub use whisper::{Language, Whisper};
+pub use whisper::{Language, TranscribeError, TranscribeOptions, Whisper, SAMPLE_RATE};
+pub use whisper_rs::{SegmentCallbackData, WhisperState};
 
+mod cache;
 mod ffmpeg_decoder;
+mod live;
 mod model;
 mod transcript;
 mod utils;
@@ -17,15 +22,26 @@ pub async fn transcribe_audio<P: AsRef<Path>, Q: AsRef<Path>, F>(
     model: Q,
     prompt: Option<&str>,
     response_format: Option<&str>,
-    _temperature: Option<f32>,
-    _lang: Option<&str>,
+    temperature: Option<f32>,
+    lang: Option<&str>,
     progress: F,
 ) -> Result<String>
 where
     F: FnMut(i32) + 'static,
 {
+    let options = TranscribeOptions {
+        prompt: prompt.map(ToString::to_string),
+        temperature,
+        language: lang
+            .map(str::parse)
+            .transpose()
+            .map_err(anyhow::Error::msg)?,
+        ..TranscribeOptions::default()
+    };
+
     let whisper = Whisper::from_model_path(model, Some(Language::Auto)).await;
-    let transcript = whisper.transcribe(audio, false, false, prompt, progress)?;
+    let mut state = whisper.create_state()?;
+    let transcript = whisper.transcribe_with_state(&mut state, audio, &options, progress)?;
 
     let response_format = response_format.unwrap_or("text");
     match response_format {
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use whisper_rs::WhisperState;
+
+use crate::whisper::{TranscribeOptions, Whisper, SAMPLE_RATE};
+
+/// Audio carried over from a finished window into the next one, so words cut at the
+/// boundary are heard in full at least once.
+const KEEP: Duration = Duration::from_millis(200);
+
+/// Text produced by a [`LiveTranscriber`] step.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LiveUpdate {
+    /// Best guess for the current window, replaced by the next update.
+    Partial(String),
+    /// Text of a completed window, it won't change anymore.
+    Final(String),
+}
+
+/// Transcribes a continuous audio stream with a sliding window.
+///
+/// Every `step` of new audio the current window is transcribed again and reported as
+/// [`LiveUpdate::Partial`]. Once the window reaches `length` its text is reported as
+/// [`LiveUpdate::Final`] and a new window starts, overlapping the previous one slightly.
+/// Words repeated because of that overlap are removed from the new window's text.
+pub struct LiveTranscriber {
+    step: usize,
+    length: usize,
+    keep: usize,
+    window: Vec<f32>,
+    /// Samples pushed since the window was last transcribed.
+    pending: usize,
+    /// Whether the window holds audio that was not part of a final update yet.
+    unfinished: bool,
+    /// Text of the last finished window.
+    previous: String,
+}
+
+impl LiveTranscriber {
+    pub fn new(step: Duration, length: Duration) -> Self {
+        let step = samples(step).max(1);
+        Self {
+            step,
+            length: samples(length).max(step),
+            keep: samples(KEEP),
+            window: Vec::new(),
+            pending: 0,
+            unfinished: false,
+            previous: String::new(),
+        }
+    }
+
+    /// Appends 16 kHz mono samples to the stream.
+    ///
+    /// The window never grows past `length`: when audio arrives faster than it is
+    /// transcribed, the oldest samples are dropped. Returns how many were.
+    pub fn push(&mut self, audio: &[f32]) -> usize {
+        self.window.extend_from_slice(audio);
+        self.pending += audio.len();
+        self.unfinished |= !audio.is_empty();
+
+        let dropped = self.window.len().saturating_sub(self.length);
+        self.window.drain(..dropped);
+        self.pending = self.pending.min(self.window.len());
+        dropped
+    }
+
+    /// Whether enough audio arrived since the last step to transcribe again.
+    pub const fn is_ready(&self) -> bool {
+        self.pending >= self.step
+    }
+
+    /// Transcribes the current window.
+    pub fn transcribe(
+        &mut self,
+        whisper: &Whisper,
+        state: &mut WhisperState,
+        options: &TranscribeOptions,
+    ) -> Result<LiveUpdate> {
+        self.pending = 0;
+        let transcript = whisper.transcribe_samples_with_state(state, &self.window, options)?;
+        let text = transcript.as_text().replace('\n', " ").trim().to_string();
+        let update = remove_overlap(&self.previous, &text);
+
+        if self.window.len() < self.length {
+            return Ok(LiveUpdate::Partial(update));
+        }
+
+        self.window
+            .drain(..self.window.len().saturating_sub(self.keep));
+        self.previous = text;
+        self.unfinished = false;
+        Ok(LiveUpdate::Final(update))
+    }
+
+    /// Transcribes whatever audio is left once the stream ended.
+    pub fn finish(
+        &mut self,
+        whisper: &Whisper,
+        state: &mut WhisperState,
+        options: &TranscribeOptions,
+    ) -> Result<Option<LiveUpdate>> {
+        if !self.unfinished {
+            return Ok(None);
+        }
+
+        // treat the rest as a full window, so it comes out final
+        self.length = 0;
+        self.transcribe(whisper, state, options).map(Some)
+    }
+}
+
+fn samples(duration: Duration) -> usize {
+    usize::try_from(duration.as_millis() * u128::from(SAMPLE_RATE) / 1000).unwrap_or(usize::MAX)
+}
+
+/// Drops the words at the start of `next` that repeat the end of `previous`.
+fn remove_overlap(previous: &str, next: &str) -> String {
+    fn normalize(word: &str) -> String {
+        word.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    }
+
+    let previous: Vec<String> = previous.split_whitespace().map(normalize).collect();
+    let words: Vec<&str> = next.split_whitespace().collect();
+    let normalized: Vec<String> = words.iter().copied().map(normalize).collect();
+
+    let overlap = (1..=previous.len().min(words.len()))
+        .rev()
+        .find(|&n| previous[previous.len() - n..] == normalized[..n])
+        .unwrap_or(0);
+
+    words[overlap..].join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_overlap() {
+        assert_eq!(
+            remove_overlap("the quick brown fox", "Fox jumps over"),
+            "jumps over"
+        );
+        assert_eq!(
+            remove_overlap("the quick brown fox", "brown fox, jumps"),
+            "jumps"
+        );
+        assert_eq!(remove_overlap("", "hello there"), "hello there");
+        assert_eq!(
+            remove_overlap("hello there", "general kenobi"),
+            "general kenobi"
+        );
+    }
+
+    #[test]
+    fn test_push_caps_window() {
+        let mut live = LiveTranscriber::new(Duration::from_secs(1), Duration::from_secs(2));
+        assert_eq!(live.push(&[0.0; 20_000]), 0);
+        assert_eq!(live.push(&[1.0; 20_000]), 8_000);
+        assert_eq!(live.window.len(), 32_000);
+        assert_eq!(live.window[12_000..], [1.0; 20_000]);
+    }
+}
@@ -1,51 +1,216 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::{convert::Infallible, net::SocketAddr};
+use std::time::Instant;
 
-use clap::Parser;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{header::CONTENT_TYPE, Body, Request, Response, Server, StatusCode};
-use multer::Multipart;
-use serde::Serialize;
-use serde_json::to_string;
+use anyhow::anyhow;
+use clap::{Parser, ValueEnum};
+use tracing_subscriber::EnvFilter;
 
-use whisper_cli::{Language, Model, Size, Whisper};
+use whisper_cli::{Language, Model, Size, TranscribeOptions, TranscriptCache, Whisper};
 
-use crate::utils::write_to;
+use crate::output::{OutputFormat, Outputs, StdoutFormat};
+use crate::server::{start_server, start_wyoming};
 
+mod batch;
+mod models;
+mod output;
+mod progress;
+mod server;
 mod utils;
 
-#[derive(Serialize)]
-struct TranscriptionResponse {
-    text: String,
-}
-
 #[derive(Parser)]
 struct Opts {
+    /// Format of the diagnostics written to stderr. Their level is set with `RUST_LOG`,
+    /// e.g. `RUST_LOG=debug`.
+    #[clap(long, value_enum, global = true, default_value = "pretty")]
+    log_format: LogFormat,
+
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// One human readable line per event
+    Pretty,
+    /// One JSON object per event, with the fields of the spans it happened in
+    Json,
+}
+
 #[derive(Parser)]
 enum SubCommand {
     #[command(about = "Start the transcription server.")]
-    Serve {
-        /// Port to listen on
-        #[clap(short, long, default_value = "8000")]
-        port: u16,
-        /// Path to the Whisper model
-        #[clap(short, long)]
-        model_path: String,
-    },
-    #[command(about = "Transcribe a given audio file.")]
+    Serve(Box<ServeArgs>),
+    #[command(about = "Serve speech-to-text over the Wyoming protocol, e.g. for Home Assistant.")]
+    ServeWyoming(WyomingArgs),
+    #[command(about = "Transcribe audio files, directories or glob patterns.")]
     Transcribe(TranscribeArgs),
+    #[command(about = "List, download, remove and verify the cached models.")]
+    Models(ModelsArgs),
+}
+
+#[derive(Parser)]
+struct ModelsArgs {
+    #[clap(subcommand)]
+    command: ModelsCommand,
+}
+
+#[derive(Parser)]
+enum ModelsCommand {
+    #[command(about = "Show every model with its status, size on disk and path.")]
+    List,
+    #[command(about = "Download models unless they are cached, and verify them.")]
+    Download {
+        #[clap(required = true)]
+        sizes: Vec<Size>,
+    },
+    #[command(about = "Remove cached models.")]
+    Rm {
+        #[clap(required = true)]
+        sizes: Vec<Size>,
+    },
+    #[command(
+        about = "Check models against their published checksums, all cached ones by default."
+    )]
+    Verify { sizes: Vec<Size> },
+    #[command(about = "Print the directory of the models, or the path of one.")]
+    Path { size: Option<Size> },
+}
+
+/// Options of `serve`. Those left out are read from `--config`, then fall back to the
+/// defaults given here.
+#[derive(Parser)]
+struct ServeArgs {
+    /// TOML file with any of the options below, using their names with `_` for `-`.
+    /// It also takes `api_keys` (a table of label to key) and the `language`, `prompt` and
+    /// `response_format` applied to requests without their own. Reloaded on SIGHUP.
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, `0.0.0.0` or `::` to accept connections from other hosts
+    /// [default: 127.0.0.1]
+    #[clap(long)]
+    host: Option<IpAddr>,
+
+    /// Port to listen on [default: 8000]
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// PEM encoded certificate chain, serves HTTPS together with `--tls-key`
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM encoded private key for `--tls-cert`
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Listen on a Unix domain socket at this path instead of TCP. The socket is
+    /// created readable and writable by its owner and group only.
+    #[cfg(unix)]
+    #[clap(long, conflicts_with_all = ["host", "port", "tls_cert"])]
+    unix_socket: Option<PathBuf>,
+
+    /// Whisper models to serve, as paths or names of downloaded models (e.g. `tiny.en`).
+    /// Repeat to serve several, requests pick one with the `model` field. The first is the
+    /// default. Named models missing from the cache are downloaded on first use. Set as
+    /// `models` in the config file.
+    #[clap(short, long, visible_alias = "model")]
+    model_path: Vec<String>,
+
+    /// Start without loading the default model, the first request waits for it instead
+    #[clap(long)]
+    no_preload: bool,
+
+    /// Number of models kept in memory, the least recently used one is unloaded beyond that
+    /// [default: 2]
+    #[clap(long)]
+    max_loaded_models: Option<usize>,
+
+    /// Number of transcriptions to run in parallel, each holding its own decoding state
+    /// [default: 1]
+    #[clap(short, long)]
+    workers: Option<usize>,
+
+    /// Number of requests allowed to wait for a free worker before new ones are rejected
+    /// [default: 16]
+    #[clap(long)]
+    queue_size: Option<usize>,
+
+    /// Seconds to keep the results of finished `/v1/jobs` around [default: 3600]
+    #[clap(long)]
+    job_ttl: Option<u64>,
+
+    /// Don't answer repeated transcriptions of the same audio and options from the cache
+    #[clap(long)]
+    no_cache: bool,
+
+    /// Directory of cached transcripts [default: whisper/transcripts in the user's cache dir]
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Size of the transcript cache in megabytes, the least recently used entries are
+    /// removed beyond that [default: 1024]
+    #[clap(long)]
+    cache_max_size: Option<u64>,
+
+    /// Days after which unused cached transcripts are removed [default: 30]
+    #[clap(long)]
+    cache_max_age: Option<u64>,
+
+    /// File of API keys, one `label:key` per line. Keys are also read from the
+    /// `WHISPER_API_KEYS` environment variable. Without any, no key is required.
+    #[clap(long)]
+    api_keys_file: Option<PathBuf>,
+
+    /// Seconds to let running transcriptions finish after SIGTERM or Ctrl-C. Queued ones
+    /// are answered with 503 right away. [default: 30]
+    #[clap(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// Largest accepted upload, in megabytes. Bigger requests are answered with 413.
+    /// [default: 25]
+    #[clap(long)]
+    max_body_size: Option<u64>,
 }
 
 #[derive(Parser)]
+struct WyomingArgs {
+    /// Address to listen on, `0.0.0.0` or `::` to accept connections from other hosts
+    #[clap(long, default_value = "127.0.0.1")]
+    host: IpAddr,
+
+    /// Port to listen on
+    #[clap(short, long, default_value = "10300")]
+    port: u16,
+
+    /// Whisper models to serve, as paths or names of models in the cache (e.g. `tiny.en`),
+    /// which are downloaded when missing. Clients pick one by name, the first is the default.
+    #[clap(short, long, visible_alias = "model", required = true)]
+    model_path: Vec<String>,
+
+    /// Language spoken to the server when clients don't name one. Detected by default.
+    #[clap(short, long)]
+    language: Option<Language>,
+
+    /// Number of models kept in memory, the least recently used one is unloaded beyond that
+    #[clap(long, default_value = "2", value_parser = at_least_one)]
+    max_loaded_models: usize,
+
+    /// Number of transcriptions to run in parallel
+    #[clap(short, long, default_value = "1", value_parser = at_least_one)]
+    workers: usize,
+
+    /// Number of transcriptions allowed to wait for a free worker
+    #[clap(long, default_value = "16")]
+    queue_size: usize,
+}
+
+#[derive(Parser)]
+#[allow(clippy::struct_excessive_bools)]
 struct TranscribeArgs {
     /// Name of the Whisper model to use
     #[clap(short, long, default_value = "medium")]
@@ -55,9 +220,10 @@ struct TranscribeArgs {
     #[clap(short, long)]
     lang: Option<Language>,
 
-    /// Path to the audio file to transcribe
-    #[clap(name = "AUDIO")]
-    audio: String,
+    /// Audio files to transcribe, `-` for standard input. Directories are searched recursively for audio files,
+    /// patterns like `'calls/**/*.wav'` match the files below their leading directory.
+    #[clap(name = "AUDIO", required = true)]
+    audio: Vec<String>,
 
     /// Toggle translation
     #[clap(short, long, default_value = "false")]
@@ -67,209 +233,119 @@ struct TranscribeArgs {
     #[clap(short, long, default_value = "false")]
     karaoke: bool,
 
-    /// Write transcription results to .txt, .vtt, and .srt files.
+    /// Write transcription results to files, by default .txt, .vtt, and .srt files next to
+    /// the audio.
     #[clap(short, long, default_value = "false")]
     write: bool,
+
+    /// Formats written with `--write`, separated by commas
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "txt,vtt,srt",
+        requires = "write"
+    )]
+    output_format: Vec<OutputFormat>,
+
+    /// Directory to write to instead of the one of each audio file
+    #[clap(long, requires = "write")]
+    output_dir: Option<PathBuf>,
+
+    /// Name of the written files, made of `{file_name}` (of the audio), `{stem}` (the file
+    /// name without extension), `{lang}` (the language of the transcript) and `{ext}`
+    #[clap(long, default_value = "{file_name}.{ext}", requires = "write")]
+    output_template: String,
+
+    /// Format of the transcript printed to stdout without `--write`
+    #[clap(long, value_enum, default_value = "text", conflicts_with = "write")]
+    stdout_format: StdoutFormat,
+
+    /// Transcribe even if the same audio was transcribed with the same options before,
+    /// without storing the result
+    #[clap(long)]
+    no_cache: bool,
+
+    /// Number of files transcribed at once, each with its own decoding state
+    #[clap(long, default_value = "1", value_parser = at_least_one)]
+    workers: usize,
+
+    /// With `--write`, transcribe files again even if all their outputs exist
+    #[clap(long)]
+    force: bool,
 }
 
 #[tokio::main]
 async fn main() {
     let opts = Opts::parse();
+    init_logging(opts.log_format);
+
     match opts.subcmd {
-        SubCommand::Serve { port, model_path } => {
-            let model_path = Path::new(&model_path);
-            start_server(port, &model_path).await;
+        SubCommand::Serve(args) => {
+            if let Err(e) = start_server(*args).await {
+                tracing::error!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        SubCommand::ServeWyoming(args) => {
+            if let Err(e) = start_wyoming(args).await {
+                tracing::error!("{e:#}");
+                std::process::exit(1);
+            }
         }
         SubCommand::Transcribe(args) => transcribe_audio(args).await,
-    }
-}
-
-const JSON_FORMAT: &str = "json";
-const TEXT_FORMAT: &str = "text";
-const SRT_FORMAT: &str = "srt";
-const VJSON_FORMAT: &str = "verbose_json";
-const VTT_FORMAT: &str = "vtt";
-
-const CONTENT_TYPE_JSON: &str = "application/json; charset=utf-8";
-const CONTENT_TYPE_TEXT: &str = "text/plain; charset=utf-8";
-
-async fn start_server(port: u16, model_path: &Path) {
-    // load model
-    let whisper = Arc::new(Mutex::new(
-        Whisper::from_model_path(model_path, Some(Language::Auto)).await,
-    ));
-
-    let make_svc = make_service_fn(move |_conn| {
-        let whisper_clone = whisper.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                handle_transcription(req, whisper_clone.clone())
-            }))
+        SubCommand::Models(args) => {
+            if let Err(e) = models::run(args.command).await {
+                tracing::error!("{e:#}");
+                std::process::exit(1);
+            }
         }
-    });
-
-    // start listening
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    let server = Server::bind(&addr).serve(make_svc);
-
-    println!("🏃‍♀️ Server running at: {}", addr);
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
     }
 }
 
-// A handler for incoming requests.
-async fn handle_transcription(
-    req: Request<Body>,
-    whisper: Arc<Mutex<Whisper>>,
-) -> Result<Response<Body>, Infallible> {
-    // Check if the request is a preflight request (OPTIONS method)
-    if req.method() == hyper::Method::OPTIONS && req.uri().path() == "/v1/audio/transcriptions" {
-        let res = Response::builder()
-            .status(StatusCode::OK)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "POST, OPTIONS")
-            .header("Access-Control-Allow-Headers", "Content-Type")
-            .body(Body::empty())
-            .unwrap();
-        return Ok(res);
-    }
-    // Extract the `multipart/form-data` boundary from the headers.
-    let boundary = req
-        .headers()
-        .get(CONTENT_TYPE)
-        .and_then(|ct| ct.to_str().ok())
-        .and_then(|ct| multer::parse_boundary(ct).ok());
-
-    // Send `BAD_REQUEST` status if the content-type is not multipart/form-data.
-    if boundary.is_none() {
-        return Ok(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .header("Access-Control-Allow-Origin", "*") // Add this for CORS
-            .body(Body::from("BAD REQUEST"))
-            .unwrap());
-    }
-
-    // Process the multipart e.g. you can store them in files.
-    let transcription_request = process_multipart(req.into_body(), boundary.unwrap()).await;
-
-    if let Err(err) = transcription_request {
-        return Ok(Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("Access-Control-Allow-Origin", "*") // Add this for CORS
-            .body(Body::from(format!("INTERNAL SERVER ERROR: {}", err)))
-            .unwrap());
+/// Sends diagnostics to stderr, above any progress bars. Stdout is left to the transcript.
+/// Parses counts of workers or models, which can't be zero.
+fn at_least_one(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(count) => Ok(count),
+        Err(e) => Err(format!("{e}")),
     }
-
-    if let Ok((trans_req, fields)) = transcription_request {
-        let audio = Path::new(trans_req.as_str());
-        let prompt = fields.get("prompt");
-        let transcript = {
-            let whisper_guard = whisper.lock().unwrap();
-            whisper_guard
-                .transcribe(audio, false, false, prompt.map(|s| s.as_str()), |_p| {})
-                .unwrap()
-        };
-        println!("time: {:?}", transcript.processing_time);
-        println!("fields: {:?}", fields);
-
-        let _lang = fields.get("language");
-        let response_format = fields
-            .get("response_format")
-            .map(|s| s.as_str())
-            .unwrap_or(JSON_FORMAT);
-        let mut is_json = false;
-        let response_body = match response_format {
-            SRT_FORMAT => transcript.as_srt(),
-            VTT_FORMAT => transcript.as_vtt(),
-            TEXT_FORMAT => transcript.as_text(),
-            &_ => {
-                is_json = true;
-                let transcript_text = transcript.as_text();
-                let response: TranscriptionResponse = TranscriptionResponse {
-                    text: transcript_text,
-                };
-                let json_response = to_string(&response).expect("Failed to serialize to JSON");
-
-                json_response
-            }
-        };
-
-        let content_type = if is_json {
-            CONTENT_TYPE_JSON
-        } else {
-            CONTENT_TYPE_TEXT
-        };
-        let response = Response::builder()
-            .header("Access-Control-Allow-Origin", "*") // Add this for CORS
-            .header("content-type", content_type)
-            .body(Body::from(response_body))
-            .unwrap();
-
-        return Ok(response);
-    }
-
-    Ok(Response::new(Body::from("Success")))
 }
 
-// Process the request body as multipart/form-data.
-async fn process_multipart(
-    body: Body,
-    boundary: String,
-) -> multer::Result<(String, HashMap<String, String>)> {
-    // Create a Multipart instance from the request body.
-    let mut multipart = Multipart::new(body, boundary);
-    let mut file_path = String::new();
-    let mut other_fields = HashMap::new();
-
-    // Iterate over the fields, `next_field` method will return the next field if
-    // available.
-    while let Some(mut field) = multipart.next_field().await? {
-        if field.name() == Some("file") {
-            // Get the field name.
-            let name = field.name();
-
-            // Get the field's filename if provided in "Content-Disposition" header.
-            let file_name = field.file_name();
-
-            // Get the "Content-Type" header as `mime::Mime` type.
-            let content_type = field.content_type();
-
-            println!(
-                "Name: {:?}, FileName: {:?}, Content-Type: {:?}",
-                name, file_name, content_type
-            );
-            // Process the field data chunks e.g. store them in a file.
-            let mut bytes_len = 0;
-            let mut audio_data = Vec::new();
-            while let Some(field_chunk) = field.chunk().await? {
-                audio_data.extend_from_slice(&field_chunk);
-                bytes_len += field_chunk.len();
-            }
-            println!("Bytes Length: {:?}", bytes_len);
-            let file_name_str: &str = field.file_name().as_ref().unwrap_or(&"audio.wav");
-            file_path = format!("/tmp/{}", file_name_str); // Adjust as necessary
-            fs::write(&file_path, audio_data).expect("Failed to write to file");
-            println!("Write the file to {}", file_path);
-        } else {
-            let mut value = String::new();
-            while let Some(field_chunk) = field.chunk().await? {
-                value.push_str(&String::from_utf8_lossy(&field_chunk));
-            }
-            if let Some(field_name) = field.name() {
-                other_fields.insert(field_name.to_string(), value);
-            }
-        }
+fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(|| progress::Stderr);
+    match format {
+        LogFormat::Pretty => logs.init(),
+        LogFormat::Json => logs.json().init(),
     }
-
-    Ok((file_path, other_fields))
 }
 
 async fn transcribe_audio(mut args: TranscribeArgs) {
-    let audio = Path::new(&args.audio);
-    let file_name = audio.file_name().unwrap().to_str().unwrap();
-
-    assert!(audio.exists(), "The provided audio file does not exist.");
+    let inputs = batch::collect_inputs(&args.audio).and_then(|files| {
+        let outputs = Outputs::new(
+            &args.output_format,
+            args.output_dir.take(),
+            std::mem::take(&mut args.output_template),
+        )?;
+        if args.write && files.iter().any(|file| file.as_os_str() == "-") {
+            return Err(anyhow!(
+                "audio from standard input can't be written to files, redirect stdout instead"
+            ));
+        }
+        Ok((files, outputs))
+    });
+    let (files, outputs) = match inputs {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            tracing::error!("{e:#}");
+            std::process::exit(1);
+        }
+    };
 
     if args.model.is_english_only() && (args.lang == Some(Language::Auto) || args.lang.is_none()) {
         args.lang = Some(Language::English);
@@ -280,27 +356,50 @@ async fn transcribe_audio(mut args: TranscribeArgs) {
         "The selected model only supports English."
     );
 
-    let whisper = Whisper::new(Model::new(args.model), args.lang).await;
-    let transcript = whisper
-        .transcribe(audio, args.translate, args.karaoke, None, |_p| {})
-        .unwrap();
-    println!("time: {:?}", transcript.processing_time);
-
-    if args.write {
-        write_to(
-            audio.with_file_name(format!("{file_name}.txt")),
-            &transcript.as_text(),
-        );
-        write_to(
-            audio.with_file_name(format!("{file_name}.vtt")),
-            &transcript.as_vtt(),
-        );
-        write_to(
-            audio.with_file_name(format!("{file_name}.srt")),
-            &transcript.as_srt(),
-        );
-    } else {
-        println!("");
-        println!("🔊 {}", transcript.as_text());
+    let mut whisper = Whisper::new(Model::new(args.model), args.lang).await;
+    if !args.no_cache {
+        whisper = whisper.with_cache(Arc::new(TranscriptCache::default()));
+    }
+    let options = TranscribeOptions {
+        translate: args.translate,
+        word_timestamps: args.karaoke,
+        ..TranscribeOptions::default()
+    };
+
+    let started = Instant::now();
+    let stdout = Mutex::new(());
+    let outcomes = batch::transcribe_all(
+        &whisper,
+        &files,
+        &options,
+        args.workers,
+        |audio| {
+            let lang = args.lang.filter(|lang| *lang != Language::Auto);
+            args.write && !args.force && outputs.exist(audio, lang.map(<&str>::from))
+        },
+        |audio, transcript| {
+            tracing::info!(audio = %audio.display(), "transcribed in {:?}", transcript.processing_time);
+            if args.write {
+                outputs.write(audio, transcript)?;
+            } else {
+                let text = args.stdout_format.render(transcript)?;
+                let _lock = stdout.lock().unwrap();
+                if files.len() > 1 {
+                    println!("==> {} <==", audio.display());
+                }
+                println!("{text}");
+            }
+            Ok(())
+        },
+    );
+
+    if files.len() > 1 {
+        batch::print_summary(&files, &outcomes, started.elapsed());
+    }
+    if outcomes
+        .iter()
+        .any(|outcome| matches!(outcome, batch::Outcome::Failed(_)))
+    {
+        std::process::exit(1);
     }
 }
@@ -1,6 +1,8 @@
 use crate::utils::download_file;
+use anyhow::{Context, Result};
 use dirs::cache_dir;
-use std::{fmt::Display, fs, path::PathBuf};
+use sha1::{Digest, Sha1};
+use std::{fmt::Display, fs, fs::File, io, path::PathBuf};
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 pub enum Size {
@@ -28,12 +30,23 @@ pub enum Size {
 
 impl Size {
     pub fn get_path(self) -> PathBuf {
-        let mut path = cache_dir().expect("Could not find cache directory");
-        path.push("whisper");
-        path.push("models");
-        path.push(format!("{self}.bin"));
+        Model::dir().join(format!("{self}.bin"))
+    }
 
-        path
+    /// SHA-1 of the model file, as published by whisper.cpp.
+    pub const fn checksum(self) -> &'static str {
+        match self {
+            Self::TinyEnglish => "c78c86eb1a8faa21b369bcd33207cc90d64ae9df",
+            Self::Tiny => "bd577a113a864445d4c299885e0cb97d4ba92b5f",
+            Self::BaseEnglish => "137c40403d78fd54d454da0f9bd998f78703390c",
+            Self::Base => "465707469ff3a37a2b9b8d8f89f2f99de7299dac",
+            Self::SmallEnglish => "db8a495a91d927739e50b3fc1cc4c6b8f6c2d022",
+            Self::Small => "55356645c2b361a969dfd0ef2c5a50d530afd8d5",
+            Self::MediumEnglish => "8c30f0e44ce9560643ebd10bbe50cd20eafd3723",
+            Self::Medium => "fd9727b6e1217c2f614f9b698455c4ffd82463b4",
+            Self::Large => "0f4c8e34f21cf1a914c59d8b3ce882345ad349d6",
+            Self::LargeV1 => "b1caaf735c4cc1429223d5a74f0f4d0b9b59a299",
+        }
     }
 
     pub const fn is_english_only(self) -> bool {
@@ -72,19 +85,41 @@ impl Model {
         Self { size }
     }
 
+    /// Where downloaded models are kept.
+    pub fn dir() -> PathBuf {
+        let mut path = cache_dir().expect("Could not find cache directory");
+        path.push("whisper");
+        path.push("models");
+
+        path
+    }
+
     pub fn get_path(&self) -> PathBuf {
         self.size.get_path()
     }
 
-    pub async fn download(&self) {
+    /// Whether the downloaded model matches its published checksum.
+    pub fn verify(&self) -> Result<bool> {
+        let path = self.get_path();
+        let mut file =
+            File::open(&path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+        let mut hasher = Sha1::new();
+        io::copy(&mut file, &mut hasher)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+        Ok(format!("{:x}", hasher.finalize()) == self.size.checksum())
+    }
+
+    /// Downloads the model into the cache, unless it is there already.
+    pub async fn download(&self) -> Result<()> {
         let path = self.get_path();
         if path.exists() {
-            return;
+            return Ok(());
         }
 
         let cache_dir = path.parent().expect("Failed to get cache dir");
         if !cache_dir.exists() {
-            fs::create_dir_all(cache_dir).expect("Failed to create cache dir.");
+            fs::create_dir_all(cache_dir).context("Failed to create cache dir.")?;
         }
 
         download_file(
@@ -94,6 +129,6 @@ impl Model {
             ),
             path.to_str().unwrap(),
         )
-        .await;
+        .await
     }
 }
@@ -0,0 +1,122 @@
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+
+use whisper_cli::{Model, Size};
+
+use crate::ModelsCommand;
+
+pub async fn run(command: ModelsCommand) -> Result<()> {
+    match command {
+        ModelsCommand::List => {
+            list();
+            Ok(())
+        }
+        ModelsCommand::Download { sizes } => download(&sizes).await,
+        ModelsCommand::Rm { sizes } => remove(&sizes),
+        ModelsCommand::Verify { sizes } => verify(&sizes),
+        ModelsCommand::Path { size } => {
+            let path = size.map_or_else(Model::dir, Size::get_path);
+            println!("{}", path.display());
+            Ok(())
+        }
+    }
+}
+
+/// Prints every model with whether it is downloaded, and its size on disk.
+fn list() {
+    println!("{:10}  {:10}  {:>9}  PATH", "NAME", "STATUS", "SIZE");
+    for &size in Size::value_variants() {
+        let path = size.get_path();
+        let (status, len) = fs::metadata(&path).map_or_else(
+            |_| ("missing", String::new()),
+            |metadata| {
+                (
+                    "downloaded",
+                    format!("{} MiB", metadata.len() / 1024 / 1024),
+                )
+            },
+        );
+        println!(
+            "{:10}  {status:10}  {len:>9}  {}",
+            size.to_string(),
+            path.display()
+        );
+    }
+}
+
+/// Downloads the missing models among `sizes` and checks all of them.
+async fn download(sizes: &[Size]) -> Result<()> {
+    for &size in sizes {
+        let model = Model::new(size);
+        if model.get_path().exists() {
+            println!("{size} is downloaded already");
+        } else {
+            model
+                .download()
+                .await
+                .with_context(|| format!("failed to download {size}"))?;
+        }
+
+        if !model.verify()? {
+            return Err(anyhow!(
+                "{size} doesn't match its checksum, remove it with `whisper models rm {size}` and download it again"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn remove(sizes: &[Size]) -> Result<()> {
+    for &size in sizes {
+        let path = size.get_path();
+        if !path.exists() {
+            println!("{size} is not downloaded");
+            continue;
+        }
+        fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+        println!("removed {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Checks `sizes` against their checksums, or all downloaded models without `sizes`.
+fn verify(sizes: &[Size]) -> Result<()> {
+    let sizes: Vec<_> = if sizes.is_empty() {
+        Size::value_variants()
+            .iter()
+            .copied()
+            .filter(|size| size.get_path().exists())
+            .collect()
+    } else {
+        sizes.to_vec()
+    };
+
+    let mut corrupt = Vec::new();
+    for size in sizes {
+        // `Size` ignores the width, its name doesn't
+        let name = size.to_string();
+        let model = Model::new(size);
+        if !model.get_path().exists() {
+            println!("{name:10}  missing");
+            corrupt.push(name);
+        } else if model.verify()? {
+            println!("{name:10}  ok");
+        } else {
+            println!("{name:10}  checksum mismatch");
+            corrupt.push(name);
+        }
+    }
+
+    if corrupt.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} failed verification, run `whisper models rm` and `whisper models download` on them",
+            corrupt.join(", ")
+        ))
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use globset::Glob;
+
+use whisper_cli::Transcript;
+
+use crate::utils::write_to;
+
+/// Placeholders understood in `--output-template`.
+const PLACEHOLDERS: &[&str] = &["file_name", "stem", "lang", "ext"];
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Plain text
+    Txt,
+    /// VTT subtitles
+    Vtt,
+    /// SRT subtitles
+    Srt,
+    /// The whole transcript with segments, timestamps and tokens
+    Json,
+    /// All of the above
+    All,
+}
+
+impl OutputFormat {
+    const fn ext(self) -> &'static str {
+        match self {
+            Self::Txt => "txt",
+            Self::Vtt => "vtt",
+            Self::Srt => "srt",
+            Self::Json | Self::All => "json",
+        }
+    }
+
+    fn render(self, transcript: &Transcript) -> Result<String> {
+        Ok(match self {
+            Self::Txt => transcript.as_text(),
+            Self::Vtt => transcript.as_vtt(),
+            Self::Srt => transcript.as_srt(),
+            Self::Json | Self::All => serde_json::to_string_pretty(transcript)?,
+        })
+    }
+}
+
+/// How `transcribe` prints transcripts without `--write`.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum StdoutFormat {
+    /// Plain text
+    Text,
+    /// VTT subtitles
+    Vtt,
+    /// SRT subtitles
+    Srt,
+    /// The whole transcript with segments, timestamps and tokens
+    Json,
+}
+
+impl StdoutFormat {
+    pub fn render(self, transcript: &Transcript) -> Result<String> {
+        match self {
+            Self::Text => OutputFormat::Txt,
+            Self::Vtt => OutputFormat::Vtt,
+            Self::Srt => OutputFormat::Srt,
+            Self::Json => OutputFormat::Json,
+        }
+        .render(transcript)
+    }
+}
+
+/// Where `--write` puts the transcripts of an audio file, and in which formats.
+pub struct Outputs {
+    formats: Vec<OutputFormat>,
+    /// Next to the audio file when `None`.
+    dir: Option<PathBuf>,
+    template: String,
+}
+
+// the `{...}` in strings are the placeholders of the template
+#[allow(clippy::literal_string_with_formatting_args)]
+impl Outputs {
+    pub fn new(formats: &[OutputFormat], dir: Option<PathBuf>, template: String) -> Result<Self> {
+        let formats = if formats.contains(&OutputFormat::All) {
+            vec![
+                OutputFormat::Txt,
+                OutputFormat::Vtt,
+                OutputFormat::Srt,
+                OutputFormat::Json,
+            ]
+        } else {
+            formats.iter().fold(Vec::new(), |mut formats, &format| {
+                if !formats.contains(&format) {
+                    formats.push(format);
+                }
+                formats
+            })
+        };
+
+        for part in template.split('{').skip(1) {
+            let name = part
+                .split_once('}')
+                .map(|(name, _)| name)
+                .ok_or_else(|| anyhow!("unclosed `{{` in the output template `{template}`"))?;
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(anyhow!(
+                    "unknown placeholder `{{{name}}}` in the output template, expected one of {}",
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+        }
+        if formats.len() > 1 && !template.contains("{ext}") {
+            return Err(anyhow!(
+                "the output template needs `{{ext}}` to write several formats"
+            ));
+        }
+
+        Ok(Self {
+            formats,
+            dir,
+            template,
+        })
+    }
+
+    /// Path of the `format` output of `audio` transcribed in `lang`.
+    fn path(&self, audio: &Path, format: OutputFormat, lang: &str) -> PathBuf {
+        let file_name = audio.file_name().unwrap_or_default().to_string_lossy();
+        let stem = audio.file_stem().unwrap_or_default().to_string_lossy();
+        let name = self
+            .template
+            .replace("{file_name}", &file_name)
+            .replace("{stem}", &stem)
+            .replace("{lang}", lang)
+            .replace("{ext}", format.ext());
+
+        match &self.dir {
+            Some(dir) => dir.join(name),
+            None => audio.with_file_name(name),
+        }
+    }
+
+    /// Whether every output of `audio` was written already. Without `lang`, outputs in
+    /// any language count.
+    pub fn exist(&self, audio: &Path, lang: Option<&str>) -> bool {
+        self.formats.iter().all(|&format| match lang {
+            Some(lang) => self.path(audio, format, lang).exists(),
+            None if self.template.contains("{lang}") => self.exists_in_any_language(audio, format),
+            None => self.path(audio, format, "").exists(),
+        })
+    }
+
+    fn exists_in_any_language(&self, audio: &Path, format: OutputFormat) -> bool {
+        // NUL stands in for the language, escaping leaves it alone and no path contains it
+        let path = self.path(audio, format, "\0");
+        let pattern = globset::escape(&path.to_string_lossy()).replace('\0', "*");
+        let Ok(pattern) = Glob::new(&pattern) else {
+            return false;
+        };
+        let pattern = pattern.compile_matcher();
+
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        dir.unwrap_or_else(|| Path::new("."))
+            .read_dir()
+            .is_ok_and(|mut entries| {
+                entries.any(|entry| entry.is_ok_and(|entry| pattern.is_match(entry.path())))
+            })
+    }
+
+    /// Writes the outputs of `audio`, creating the output directory if needed.
+    pub fn write(&self, audio: &Path, transcript: &Transcript) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        }
+
+        let lang = transcript.language.as_deref().unwrap_or("auto");
+        for &format in &self.formats {
+            write_to(self.path(audio, format, lang), &format.render(transcript)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_paths() {
+        let audio = Path::new("talks/keynote.mp3");
+        let outputs = Outputs::new(
+            &[OutputFormat::Txt, OutputFormat::Srt],
+            None,
+            "{file_name}.{ext}".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            outputs.path(audio, OutputFormat::Srt, "en"),
+            Path::new("talks/keynote.mp3.srt")
+        );
+
+        let outputs = Outputs::new(
+            &[OutputFormat::All],
+            Some(PathBuf::from("out")),
+            "{stem}.{lang}.{ext}".to_string(),
+        )
+        .unwrap();
+        assert_eq!(outputs.formats.len(), 4);
+        assert_eq!(
+            outputs.path(audio, OutputFormat::Json, "de"),
+            Path::new("out/keynote.de.json")
+        );
+
+        assert!(Outputs::new(&[OutputFormat::All], None, "{stem}.txt".to_string()).is_err());
+        assert!(Outputs::new(&[OutputFormat::Txt], None, "{name}.txt".to_string()).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use whisper_cli::SAMPLE_RATE;
+
+/// The bars of the files being transcribed, drawn on stderr when it is a terminal.
+static BARS: LazyLock<MultiProgress> = LazyLock::new(|| {
+    if io::stderr().is_terminal() {
+        MultiProgress::new()
+    } else {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    }
+});
+
+/// Shows the progress of transcribing `audio`, given as decoded samples, until the bar
+/// is dropped. Returns the callback to hand to whisper.
+pub fn start(audio: &Path, samples: &[f32]) -> (Bar, impl FnMut(i32) + 'static) {
+    #[allow(clippy::cast_precision_loss)]
+    let length = Duration::from_secs_f64(samples.len() as f64 / f64::from(SAMPLE_RATE));
+    let bar = BARS.add(ProgressBar::new(100)).with_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{prefix} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos:>3}% (ETA {eta}, {msg})",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    bar.set_prefix(audio.display().to_string());
+    bar.set_message("RTF -");
+
+    let started = Instant::now();
+    let callback_bar = bar.clone();
+    let callback = move |percent: i32| {
+        let percent = u64::try_from(percent.clamp(0, 100)).unwrap_or_default();
+        callback_bar.set_position(percent);
+        if percent > 0 && !length.is_zero() {
+            // processing time over the length of the audio processed so far
+            #[allow(clippy::cast_precision_loss)]
+            let done = length.as_secs_f64() * percent as f64 / 100.0;
+            callback_bar.set_message(format!("RTF {:.2}", started.elapsed().as_secs_f64() / done));
+        }
+    };
+
+    (Bar(bar), callback)
+}
+
+/// Removes its progress bar when dropped.
+pub struct Bar(ProgressBar);
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        self.0.finish_and_clear();
+        BARS.remove(&self.0);
+    }
+}
+
+/// Writes logs to stderr above the progress bars rather than through them.
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        BARS.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
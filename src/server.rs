@@ -0,0 +1,856 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::{anyhow, Context};
+use hyper::header::{HeaderName, HeaderValue, ALLOW, CONTENT_LENGTH};
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use serde_json::to_string;
+use tempfile::{TempDir, TempPath};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use tokio::{signal, time};
+use tracing::{field, Instrument, Span};
+use uuid::Uuid;
+
+use whisper_cli::{Language, TranscribeOptions, Transcript, Utternace};
+
+use crate::ServeArgs;
+use auth::{unauthorized_response, ApiKeys, Unauthorized};
+use config::{Config, Settings};
+use error::ApiError;
+use jobs::{handle_create_job, handle_job, JobStore};
+use metrics::{Metrics, ResponseFormat, CONTENT_TYPE_METRICS};
+use models::ModelRegistry;
+use upload::{process_multipart, UploadError};
+
+mod auth;
+mod config;
+mod error;
+mod jobs;
+mod listen;
+mod live;
+mod metrics;
+mod models;
+mod pool;
+mod sse;
+mod upload;
+mod wyoming;
+
+pub use wyoming::start_wyoming;
+
+#[derive(Serialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct VerboseTranscriptionResponse {
+    task: &'static str,
+    language: String,
+    duration: f64,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segments: Option<Vec<VerboseSegment>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    words: Option<Vec<VerboseWord>>,
+}
+
+#[derive(Serialize)]
+struct VerboseSegment {
+    id: usize,
+    seek: i64,
+    start: f64,
+    end: f64,
+    text: String,
+    tokens: Vec<i32>,
+    temperature: f32,
+    avg_logprob: f32,
+    compression_ratio: f32,
+    no_speech_prob: f32,
+}
+
+#[derive(Serialize)]
+struct VerboseWord {
+    word: String,
+    start: f64,
+    end: f64,
+}
+
+impl VerboseTranscriptionResponse {
+    fn new(transcript: &Transcript, options: &TranscribeOptions, granularities: &[String]) -> Self {
+        let mut windows = seeks(&transcript.utterances).into_iter();
+        let segments = granularities.iter().any(|g| g == "segment").then(|| {
+            transcript
+                .utterances
+                .iter()
+                .enumerate()
+                .map(|(id, utterance)| VerboseSegment {
+                    id,
+                    seek: windows.next().unwrap_or_default(),
+                    start: to_seconds(utterance.start),
+                    end: to_seconds(utterance.stop),
+                    text: utterance.text.clone(),
+                    tokens: utterance.tokens.clone(),
+                    temperature: options.temperature.unwrap_or_default(),
+                    avg_logprob: utterance.avg_logprob,
+                    compression_ratio: utterance.compression_ratio(),
+                    // whisper.cpp does not expose the no-speech probability.
+                    no_speech_prob: 0.0,
+                })
+                .collect()
+        });
+        let words = granularities
+            .iter()
+            .any(|g| g == "word")
+            .then_some(transcript.word_utterances.as_ref())
+            .flatten()
+            .map(|words| {
+                words
+                    .iter()
+                    .map(|word| VerboseWord {
+                        word: word.text.trim().to_string(),
+                        start: to_seconds(word.start),
+                        end: to_seconds(word.stop),
+                    })
+                    .collect()
+            });
+
+        Self {
+            task: if options.translate {
+                "translate"
+            } else {
+                "transcribe"
+            },
+            // the full name, e.g. "english", like OpenAI
+            language: transcript
+                .language
+                .as_deref()
+                .and_then(whisper_rs::get_lang_id)
+                .and_then(whisper_rs::get_lang_str_full)
+                .unwrap_or_default()
+                .to_string(),
+            duration: transcript.duration.as_secs_f64(),
+            text: transcript.as_text(),
+            segments,
+            words,
+        }
+    }
+}
+
+/// Length of the audio whisper decodes at once, in 10ms mel frames.
+const WINDOW_FRAMES: i64 = 3000;
+
+/// The `seek` of each segment: the offset, in mel frames, of the 30 second window it was
+/// decoded in. whisper.cpp doesn't report its windows, but like `OpenAI`'s implementation it
+/// starts each one where the last segment of the previous one ended, so they are
+/// recovered from the segment timestamps (in 10ms units, the mel frame hop).
+fn seeks(utterances: &[Utternace]) -> Vec<i64> {
+    let mut seek = 0;
+    let mut previous_stop = 0;
+    utterances
+        .iter()
+        .map(|utterance| {
+            if utterance.stop > seek + WINDOW_FRAMES {
+                seek = previous_stop;
+            }
+            previous_stop = utterance.stop;
+            seek
+        })
+        .collect()
+}
+
+/// Converts a whisper timestamp (in 10ms units) to seconds.
+#[allow(clippy::cast_precision_loss)]
+fn to_seconds(timestamp: i64) -> f64 {
+    timestamp as f64 / 100.0
+}
+
+const JSON_FORMAT: &str = "json";
+const TEXT_FORMAT: &str = "text";
+const SRT_FORMAT: &str = "srt";
+const VJSON_FORMAT: &str = "verbose_json";
+const VTT_FORMAT: &str = "vtt";
+/// Every `response_format` understood, in the order they are listed in errors.
+const RESPONSE_FORMATS: [&str; 5] = [
+    JSON_FORMAT,
+    TEXT_FORMAT,
+    SRT_FORMAT,
+    VTT_FORMAT,
+    VJSON_FORMAT,
+];
+
+const TRANSCRIPTIONS_PATH: &str = "/v1/audio/transcriptions";
+const TRANSLATIONS_PATH: &str = "/v1/audio/translations";
+const LIVE_PATH: &str = "/v1/audio/live";
+
+const CONTENT_TYPE_JSON: &str = "application/json; charset=utf-8";
+const CONTENT_TYPE_TEXT: &str = "text/plain; charset=utf-8";
+
+const HEALTH_PATH: &str = "/health";
+const READY_PATH: &str = "/health/ready";
+const MODELS_PATH: &str = "/v1/models";
+const JOBS_PATH: &str = "/v1/jobs";
+const METRICS_PATH: &str = "/metrics";
+
+/// Header carrying the id requests are logged under. A client supplied id is kept, so
+/// the logs of a proxy in front of the server can be matched up.
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Shared state handed to every request.
+struct AppState {
+    models: ModelRegistry,
+    api_keys: RwLock<ApiKeys>,
+    settings: RwLock<Arc<Settings>>,
+    metrics: Arc<Metrics>,
+    jobs: JobStore,
+    /// Where uploads are stored while they are transcribed, emptied on shutdown.
+    uploads: TempDir,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    model_loaded: bool,
+}
+
+#[derive(Serialize)]
+struct ModelList {
+    object: &'static str,
+    data: Vec<ModelObject>,
+}
+
+#[derive(Serialize)]
+struct ModelObject {
+    id: String,
+    object: &'static str,
+    created: u64,
+    owned_by: &'static str,
+}
+
+impl AppState {
+    /// The settings in effect, a request keeps the ones it started with across a reload.
+    fn settings(&self) -> Arc<Settings> {
+        Arc::clone(&self.settings.read().unwrap())
+    }
+
+    /// Switches to the keys, limits and models of a reloaded config.
+    async fn apply(&self, config: &Config) {
+        self.models
+            .update(
+                &config.models,
+                config.workers,
+                config.queue_size,
+                config.max_loaded_models,
+            )
+            .await;
+        *self.api_keys.write().unwrap() = config.api_keys.clone();
+        *self.settings.write().unwrap() = Arc::new(config.settings.clone());
+    }
+}
+
+pub async fn start_server(args: ServeArgs) -> anyhow::Result<()> {
+    let config = Arc::new(Config::load(&args)?);
+    if config.api_keys.is_enabled() {
+        tracing::info!(keys = config.api_keys.len(), "requests need an API key");
+    }
+
+    let state = Arc::new(AppState {
+        models: ModelRegistry::new(
+            &config.models,
+            config.workers,
+            config.queue_size,
+            config.max_loaded_models,
+        )
+        .with_cache(config.cache.clone()),
+        api_keys: RwLock::new(config.api_keys.clone()),
+        settings: RwLock::new(Arc::new(config.settings.clone())),
+        metrics: Arc::new(Metrics::new()),
+        jobs: JobStore::new(Duration::from_secs(config.job_ttl)),
+        uploads: tempfile::Builder::new()
+            .prefix("whisper-uploads-")
+            .tempdir()
+            .context("failed to create the upload directory")?,
+    });
+
+    // forget finished jobs once their TTL is up
+    let sweeper = Arc::clone(&state);
+    tokio::spawn(async move {
+        // `interval` panics on a zero period, which a zero TTL would give
+        let period = sweeper
+            .jobs
+            .ttl()
+            .clamp(Duration::from_secs(1), Duration::from_secs(30));
+        let mut interval = time::interval(period);
+        loop {
+            interval.tick().await;
+            sweeper.jobs.remove_expired();
+        }
+    });
+
+    // load the default model in the background, so health checks can report readiness meanwhile
+    if config.preload {
+        let loader = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = loader.models.preload().await {
+                tracing::error!("{e}");
+                std::process::exit(1);
+            }
+        });
+    } else {
+        state.models.skip_preload();
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(
+        args,
+        Arc::clone(&state),
+        Arc::clone(&config),
+    ));
+
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    let listening = listen(&config, Arc::clone(&state), shutdown.clone());
+    tokio::pin!(listening);
+    let mut signalled = shutdown;
+    tokio::select! {
+        // the listener stops as soon as it sees the signal too, drain first
+        biased;
+        _ = signalled.changed() => {}
+        // failed to bind, or stopped without being asked to
+        served = &mut listening => return served,
+    }
+
+    // stop taking work, then give running transcriptions time to finish
+    tracing::info!(
+        "shutting down, waiting up to {}s for running transcriptions",
+        config.shutdown_timeout
+    );
+    let drained = time::timeout(Duration::from_secs(config.shutdown_timeout), async {
+        state.models.close().await;
+        let served = listening.await;
+        while !state.models.is_idle().await {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        served
+    })
+    .await;
+
+    // uploads of transcriptions that were cut short would stay behind otherwise
+    let _ = fs::remove_dir_all(state.uploads.path());
+
+    drained.unwrap_or_else(|_| {
+        Err(anyhow!(
+            "shutdown timed out after {}s, running transcriptions were aborted",
+            config.shutdown_timeout
+        ))
+    })
+}
+
+/// Reloads the config on SIGHUP. API keys, limits, request defaults and the model list
+/// change in place, a broken config is reported and the current one kept.
+#[cfg(unix)]
+async fn reload_on_hangup(args: ServeArgs, state: Arc<AppState>, started: Arc<Config>) {
+    let Ok(mut hangup) = signal::unix::signal(signal::unix::SignalKind::hangup()) else {
+        return;
+    };
+
+    while hangup.recv().await.is_some() {
+        let config = match Config::load(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!(
+                    "failed to reload the configuration, keeping the current one: {e:#}"
+                );
+                continue;
+            }
+        };
+
+        state.apply(&config).await;
+        tracing::info!(
+            models = config.models.len(),
+            keys = config.api_keys.len(),
+            "reloaded the configuration"
+        );
+        let ignored = started.restart_required(&config);
+        if !ignored.is_empty() {
+            tracing::warn!("changes to {} take effect on restart", ignored.join(", "));
+        }
+
+        // the default model may have changed
+        if config.preload {
+            let loader = Arc::clone(&state);
+            tokio::spawn(async move {
+                if let Err(e) = loader.models.preload().await {
+                    tracing::error!("{e}");
+                }
+            });
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or on the SIGTERM sent by container runtimes and init systems.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    if let Ok(mut terminate) = signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        return;
+    }
+
+    let _ = signal::ctrl_c().await;
+}
+
+/// Serves on the Unix socket, TLS or plain TCP listener picked by the config until
+/// `shutdown` is signalled and every connection is closed.
+async fn listen(
+    config: &Config,
+    state: Arc<AppState>,
+    shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        let incoming = listen::unix(path)?;
+        tracing::info!("listening on unix:{}", path.display());
+        let served = serve(incoming, state, shutdown).await;
+        let _ = fs::remove_file(path);
+        return Ok(served?);
+    }
+
+    let addr = SocketAddr::new(config.host, config.port);
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        let incoming = listen::tls(addr, cert, key).await?;
+        tracing::info!("listening on https://{addr}");
+        return Ok(serve(incoming, state, shutdown).await?);
+    }
+
+    let incoming = AddrIncoming::bind(&addr)?;
+    tracing::info!("listening on http://{addr}");
+    Ok(serve(incoming, state, shutdown).await?)
+}
+
+async fn serve<A>(
+    incoming: A,
+    state: Arc<AppState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> hyper::Result<()>
+where
+    A: Accept,
+    A::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    A::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_svc = make_service_fn(move |_conn: &A::Conn| {
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = Arc::clone(&state);
+                async move { Ok::<_, Infallible>(route(req, state).await) }
+            }))
+        }
+    });
+
+    Server::builder(incoming)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.changed().await;
+        })
+        .await
+}
+
+/// Handles a request in a span named after its id, counting it for `/metrics`.
+async fn route(req: Request<Body>, state: Arc<AppState>) -> Response<Body> {
+    let path = req.uri().path().to_owned();
+    let id = req
+        .headers()
+        .get(&REQUEST_ID)
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .and_then(|id| id.to_str().ok())
+        .map_or_else(|| Uuid::new_v4().to_string(), ToString::to_string);
+    let span = tracing::info_span!(
+        "request",
+        %id,
+        method = %req.method(),
+        %path,
+        key = field::Empty,
+    );
+
+    let started = Instant::now();
+    let mut response = dispatch(req, Arc::clone(&state))
+        .instrument(span.clone())
+        .await;
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            elapsed = ?started.elapsed(),
+            "answered"
+        );
+    });
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, id);
+    }
+
+    state.metrics.record_request(
+        &path,
+        response.status(),
+        response.extensions().get::<ResponseFormat>().copied(),
+    );
+    response
+}
+
+/// Dispatches a request to its handler, answering 404 for unknown paths and
+/// 405 for known paths hit with the wrong method.
+async fn dispatch(req: Request<Body>, state: Arc<AppState>) -> Response<Body> {
+    let path = req.uri().path().to_owned();
+
+    // preflight requests and health checks come without credentials
+    if req.method() != Method::OPTIONS && path != HEALTH_PATH && path != READY_PATH {
+        match state.api_keys.read().unwrap().authorize(&req) {
+            Ok(Some(label)) => {
+                Span::current().record("key", label);
+            }
+            Ok(None) => {}
+            Err(Unauthorized) => return unauthorized_response(),
+        }
+    }
+
+    if let Some(job) = path.strip_prefix("/v1/jobs/") {
+        return if req.method() == Method::GET {
+            handle_job(&req, &state, job).unwrap_or_else(ApiError::into_response)
+        } else {
+            method_not_allowed("GET")
+        };
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, TRANSCRIPTIONS_PATH) => handle_transcription(req, state, false)
+            .await
+            .unwrap_or_else(ApiError::into_response),
+        (&Method::POST, TRANSLATIONS_PATH) => handle_transcription(req, state, true)
+            .await
+            .unwrap_or_else(ApiError::into_response),
+        (&Method::OPTIONS, TRANSCRIPTIONS_PATH | TRANSLATIONS_PATH) => preflight("POST, OPTIONS"),
+        (&Method::GET, HEALTH_PATH) => handle_health(&state, false),
+        (&Method::GET, READY_PATH) => handle_health(&state, true),
+        (&Method::GET, MODELS_PATH) => handle_models(&state),
+        (&Method::GET, METRICS_PATH) => handle_metrics(&state),
+        (&Method::POST, JOBS_PATH) => handle_create_job(req, state)
+            .await
+            .unwrap_or_else(ApiError::into_response),
+        (&Method::GET, LIVE_PATH) => live::handle_live(req, state).await,
+        (_, TRANSCRIPTIONS_PATH | TRANSLATIONS_PATH) => method_not_allowed("POST, OPTIONS"),
+        (_, HEALTH_PATH | READY_PATH | MODELS_PATH | METRICS_PATH | LIVE_PATH) => {
+            method_not_allowed("GET")
+        }
+        (_, JOBS_PATH) => method_not_allowed("POST"),
+        _ => ApiError::not_found(format!("no route for {path}")).into_response(),
+    }
+}
+
+/// Answers liveness checks, which succeed while the process is up, and with `readiness`
+/// readiness checks, which fail until the default model is loaded. Preloading includes
+/// downloading the model, so failing liveness meanwhile would get the server restarted.
+fn handle_health(state: &AppState, readiness: bool) -> Response<Body> {
+    // without preloading the server is ready before any model is loaded
+    let ready = state.models.is_ready();
+    let status = if ready || !readiness {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    json_response(
+        status,
+        &HealthResponse {
+            status: if ready { "ok" } else { "loading" },
+            model_loaded: state.models.is_loaded(),
+        },
+    )
+}
+
+fn handle_models(state: &AppState) -> Response<Body> {
+    json_response(
+        StatusCode::OK,
+        &ModelList {
+            object: "list",
+            data: state
+                .models
+                .list()
+                .into_iter()
+                .map(|(id, created)| ModelObject {
+                    id,
+                    object: "model",
+                    created,
+                    owned_by: "whisper-cli",
+                })
+                .collect(),
+        },
+    )
+}
+
+fn handle_metrics(state: &AppState) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, CONTENT_TYPE_METRICS)
+        .body(Body::from(state.metrics.render(&state.models)))
+        .unwrap()
+}
+
+// Answer CORS preflight requests.
+fn preflight(allow: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", allow)
+        .header(
+            "Access-Control-Allow-Headers",
+            "Authorization, Content-Type",
+        )
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    let mut response = ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "invalid_request_error",
+        format!("method not allowed, expected {allow}"),
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(allow));
+    response
+}
+
+fn text_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*") // Add this for CORS
+        .header(CONTENT_TYPE, CONTENT_TYPE_TEXT)
+        .body(body.into())
+        .unwrap()
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*") // Add this for CORS
+        .header(CONTENT_TYPE, CONTENT_TYPE_JSON)
+        .body(Body::from(
+            to_string(value).expect("Failed to serialize to JSON"),
+        ))
+        .unwrap()
+}
+
+/// The fields of a transcription upload the server acts on.
+struct TranscriptionRequest {
+    /// The uploaded audio, removed from disk once dropped.
+    audio: TempPath,
+    /// Name of the model to transcribe with, the default one when missing.
+    model: Option<String>,
+    options: TranscribeOptions,
+    response_format: String,
+    granularities: Vec<String>,
+    /// Form fields not covered above.
+    fields: HashMap<String, String>,
+}
+
+// A handler for transcription and translation requests.
+async fn handle_transcription(
+    req: Request<Body>,
+    state: Arc<AppState>,
+    translate: bool,
+) -> Result<Response<Body>, ApiError> {
+    let mut request = read_transcription_request(req, &state).await?;
+    request.options.translate = translate;
+
+    let pool = state.models.get(request.model.as_deref()).await?;
+
+    if request.fields.get("stream").is_some_and(|s| s == "true") {
+        return sse::stream_transcription(&pool, request, Arc::clone(&state.metrics));
+    }
+
+    let TranscriptionRequest {
+        audio,
+        options,
+        response_format,
+        granularities,
+        ..
+    } = request;
+    let job_options = options.clone();
+    let transcript = pool
+        .run(move |whisper, state| {
+            whisper.transcribe_with_state(state, &audio, &job_options, |_p| {})
+        })
+        .await?
+        .map_err(|e| ApiError::transcription(&e))?;
+    tracing::info!(
+        audio = ?transcript.duration,
+        elapsed = ?transcript.processing_time,
+        "transcribed"
+    );
+    state.metrics.record_transcript(&transcript);
+
+    Ok(transcript_response(
+        &transcript,
+        &response_format,
+        &options,
+        &granularities,
+    ))
+}
+
+/// Parses a `multipart/form-data` transcription upload, storing the audio on disk.
+async fn read_transcription_request(
+    req: Request<Body>,
+    state: &AppState,
+) -> Result<TranscriptionRequest, ApiError> {
+    let settings = state.settings();
+    let max_body_size = settings.max_body_size;
+    // Reject oversized bodies up front when the client announces their size.
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > max_body_size) {
+        return Err(UploadError::TooLarge.into());
+    }
+
+    // Extract the `multipart/form-data` boundary from the headers.
+    let boundary = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .and_then(|ct| multer::parse_boundary(ct).ok());
+
+    // Send `BAD_REQUEST` status if the content-type is not multipart/form-data.
+    let Some(boundary) = boundary else {
+        return Err(ApiError::invalid_request(
+            "expected a multipart/form-data body",
+        ));
+    };
+
+    // Process the multipart e.g. you can store them in files.
+    let (audio, mut fields) = process_multipart(
+        req.into_body(),
+        boundary,
+        max_body_size,
+        state.uploads.path(),
+    )
+    .await?;
+    tracing::debug!(?fields, "read the upload");
+
+    let language = fields
+        .remove("language")
+        .filter(|language| !language.is_empty())
+        .map(|language| language.parse::<Language>())
+        .transpose()
+        .map_err(|e| {
+            ApiError::invalid_request(e)
+                .with_param("language")
+                .with_code("invalid_language")
+        })?
+        .or(settings.language);
+    let temperature = fields
+        .remove("temperature")
+        .map(|temperature| temperature.trim().parse::<f32>())
+        .transpose()
+        .ok()
+        .and_then(|temperature| match temperature {
+            Some(t) if !(0.0..=1.0).contains(&t) => None,
+            temperature => Some(temperature),
+        })
+        .ok_or_else(|| {
+            ApiError::invalid_request("temperature must be a number between 0 and 1")
+                .with_param("temperature")
+        })?;
+    let granularities: Vec<String> = fields.remove("timestamp_granularities[]").map_or_else(
+        || vec!["segment".to_string()],
+        |g| g.split(',').map(|g| g.trim().to_string()).collect(),
+    );
+
+    Ok(TranscriptionRequest {
+        audio,
+        model: fields.remove("model"),
+        options: TranscribeOptions {
+            word_timestamps: granularities.iter().any(|g| g == "word"),
+            prompt: fields.remove("prompt").or_else(|| settings.prompt.clone()),
+            language,
+            temperature,
+            ..TranscribeOptions::default()
+        },
+        response_format: fields.remove("response_format").map_or_else(
+            || Ok(settings.response_format.clone()),
+            |format| check_response_format(&format, "response_format").map(|()| format),
+        )?,
+        granularities,
+        fields,
+    })
+}
+
+/// Rejects a `format` that isn't one of [`RESPONSE_FORMATS`], naming `param` as its source.
+fn check_response_format(format: &str, param: &'static str) -> Result<(), ApiError> {
+    if RESPONSE_FORMATS.contains(&format) {
+        Ok(())
+    } else {
+        Err(ApiError::invalid_request(format!(
+            "unknown {param} `{format}`, expected one of {}",
+            RESPONSE_FORMATS.join(", ")
+        ))
+        .with_param(param))
+    }
+}
+
+/// Renders a transcript in one of the `OpenAI` response formats, see
+/// [`check_response_format`].
+fn transcript_response(
+    transcript: &Transcript,
+    response_format: &str,
+    options: &TranscribeOptions,
+    granularities: &[String],
+) -> Response<Body> {
+    let (format, mut response) = match response_format {
+        SRT_FORMAT => (
+            SRT_FORMAT,
+            text_response(StatusCode::OK, transcript.as_srt()),
+        ),
+        VTT_FORMAT => (
+            VTT_FORMAT,
+            text_response(StatusCode::OK, transcript.as_vtt()),
+        ),
+        TEXT_FORMAT => (
+            TEXT_FORMAT,
+            text_response(StatusCode::OK, transcript.as_text()),
+        ),
+        VJSON_FORMAT => (
+            VJSON_FORMAT,
+            json_response(
+                StatusCode::OK,
+                &VerboseTranscriptionResponse::new(transcript, options, granularities),
+            ),
+        ),
+        _ => (
+            JSON_FORMAT,
+            json_response(
+                StatusCode::OK,
+                &TranscriptionResponse {
+                    text: transcript.as_text(),
+                },
+            ),
+        ),
+    };
+    response.extensions_mut().insert(ResponseFormat(format));
+    response
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use hyper::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+
+use super::error::ApiError;
+
+/// Environment variable holding API keys, in the same format as the keys file
+/// with `,` or newlines between entries.
+pub const API_KEYS_ENV: &str = "WHISPER_API_KEYS";
+
+/// Keys accepted in the `Authorization: Bearer` header, each with a label naming its owner.
+///
+/// Keys are given one per entry as `label:key`, or just `key` to have the label
+/// derived from its position. Blank lines and lines starting with `#` are skipped.
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    /// Labels by key.
+    keys: HashMap<String, String>,
+}
+
+/// The request came without one of the configured keys.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl ApiKeys {
+    /// Reads keys from `file` if given, from [`API_KEYS_ENV`] if set, and adds `inline`
+    /// ones given as label to key.
+    pub fn load(file: Option<&Path>, inline: &HashMap<String, String>) -> Result<Self> {
+        let mut keys = Self::default();
+        if let Some(file) = file {
+            let content = fs::read_to_string(file)
+                .with_context(|| format!("failed to read API keys from {}", file.display()))?;
+            keys.parse(&content)?;
+        }
+        if let Ok(content) = std::env::var(API_KEYS_ENV) {
+            keys.parse(&content.replace(',', "\n"))?;
+        }
+        for (label, key) in inline {
+            keys.insert(label.clone(), key.trim())?;
+        }
+
+        Ok(keys)
+    }
+
+    fn parse(&mut self, content: &str) -> Result<()> {
+        let entries = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for entry in entries {
+            let (label, key) = entry.split_once(':').map_or_else(
+                || (format!("key-{}", self.keys.len() + 1), entry),
+                |(label, key)| (label.trim().to_string(), key.trim()),
+            );
+            self.insert(label, key)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `key`, skipping it when empty, as it would only be a way around the check.
+    fn insert(&mut self, label: String, key: &str) -> Result<()> {
+        if key.is_empty() {
+            tracing::warn!(label, "skipped an empty API key");
+            return Ok(());
+        }
+        match self.keys.get(key) {
+            Some(other) if *other != label => Err(anyhow!(
+                "the API keys of `{other}` and `{label}` are the same"
+            )),
+            _ => {
+                self.keys.insert(key.to_string(), label);
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether requests need a key at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// The label of the key `req` was sent with, if it is one of ours.
+    fn label(&self, req: &Request<Body>) -> Option<&str> {
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?
+            .trim();
+
+        // compare every key in full, so the time taken doesn't hint at a matching prefix
+        self.keys.iter().fold(None, |found, (key, label)| {
+            if constant_time_eq(key.as_bytes(), token.as_bytes()) {
+                Some(label.as_str())
+            } else {
+                found
+            }
+        })
+    }
+
+    /// Checks the request's key, returning its label. Passes everything when no keys
+    /// are configured.
+    pub fn authorize(&self, req: &Request<Body>) -> Result<Option<&str>, Unauthorized> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        self.label(req).map(Some).ok_or(Unauthorized)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The `OpenAI`-style answer to requests without a valid key.
+pub fn unauthorized_response() -> Response<Body> {
+    let mut response = ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "invalid_request_error",
+        "Incorrect API key provided. Send it as `Authorization: Bearer <key>`.",
+    )
+    .with_code("invalid_api_key")
+    .into_response();
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: &str) -> Request<Body> {
+        Request::builder()
+            .header(AUTHORIZATION, authorization)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_authorize() {
+        let mut keys = ApiKeys::default();
+        keys.parse("# team keys\nalice: sk-alice\n\nsk-anonymous\nbob:\n")
+            .unwrap();
+
+        assert_eq!(
+            keys.authorize(&request("Bearer sk-alice")).unwrap(),
+            Some("alice")
+        );
+        assert_eq!(
+            keys.authorize(&request("Bearer sk-anonymous")).unwrap(),
+            Some("key-2")
+        );
+        assert!(keys.authorize(&request("Bearer sk-alic")).is_err());
+        assert!(keys.authorize(&request("sk-alice")).is_err());
+        assert!(ApiKeys::default()
+            .authorize(&request("Bearer anything"))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let mut keys = ApiKeys::default();
+        keys.parse("alice:\nbob: \n").unwrap();
+        assert!(!keys.is_enabled());
+
+        // inline keys from the config file, without reading the environment like `load`
+        keys.insert("carol".to_string(), "").unwrap();
+        assert!(!keys.is_enabled());
+
+        assert!(keys.parse("alice: sk-same\nbob: sk-same\n").is_err());
+        assert!(keys.parse("alice: sk-same\n").is_ok());
+        assert!(keys.insert("carol".to_string(), "sk-same").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use whisper_cli::{Language, TranscriptCache};
+
+use super::auth::ApiKeys;
+use super::{JSON_FORMAT, RESPONSE_FORMATS};
+use crate::ServeArgs;
+
+/// The `--config` TOML file. Every setting is optional, flags given on the command
+/// line take precedence.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    host: Option<IpAddr>,
+    port: Option<u16>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    unix_socket: Option<PathBuf>,
+    models: Vec<String>,
+    preload: Option<bool>,
+    max_loaded_models: Option<usize>,
+    workers: Option<usize>,
+    queue_size: Option<usize>,
+    job_ttl: Option<u64>,
+    cache: Option<bool>,
+    cache_dir: Option<PathBuf>,
+    cache_max_size: Option<u64>,
+    cache_max_age: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    max_body_size: Option<u64>,
+    api_keys_file: Option<PathBuf>,
+    /// Keys by label, next to the ones from `api_keys_file`.
+    api_keys: HashMap<String, String>,
+    /// Applied to requests that don't send their own.
+    language: Option<String>,
+    prompt: Option<String>,
+    response_format: Option<String>,
+}
+
+/// Server settings, from the command line and the config file.
+pub struct Config {
+    pub host: IpAddr,
+    pub port: u16,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub unix_socket: Option<PathBuf>,
+    pub models: Vec<String>,
+    /// Whether to load the default model before taking requests.
+    pub preload: bool,
+    pub max_loaded_models: usize,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub job_ttl: u64,
+    /// Where finished transcripts are kept, `None` with `--no-cache`.
+    pub cache: Option<TranscriptCache>,
+    pub shutdown_timeout: u64,
+    pub api_keys: ApiKeys,
+    pub settings: Settings,
+}
+
+/// Per-request settings, replaced as a whole when the config is reloaded.
+#[derive(Clone)]
+pub struct Settings {
+    /// Largest request body accepted, in bytes.
+    pub max_body_size: u64,
+    pub language: Option<Language>,
+    pub prompt: Option<String>,
+    pub response_format: String,
+}
+
+impl Config {
+    /// Reads the config file named in `args`, if any, and applies the flags over it.
+    pub fn load(args: &ServeArgs) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                toml::from_str(&content)
+                    .with_context(|| format!("invalid config file {}", path.display()))?
+            }
+            None => ConfigFile::default(),
+        };
+
+        Self::merge(args, file)
+    }
+
+    fn merge(args: &ServeArgs, file: ConfigFile) -> Result<Self> {
+        let models = if args.model_path.is_empty() {
+            file.models
+        } else {
+            args.model_path.clone()
+        };
+        if models.is_empty() {
+            return Err(anyhow!(
+                "no model to serve, pass --model-path or list `models` in the config file"
+            ));
+        }
+
+        let language = file
+            .language
+            .map(|language| language.parse::<Language>())
+            .transpose()
+            .map_err(|e| anyhow!("invalid `language` in the config file: {e}"))?;
+        let response_format = file
+            .response_format
+            .unwrap_or_else(|| JSON_FORMAT.to_string());
+        if !RESPONSE_FORMATS.contains(&response_format.as_str()) {
+            return Err(anyhow!(
+                "invalid `response_format` in the config file: {response_format}"
+            ));
+        }
+
+        let api_keys_file = args.api_keys_file.as_ref().or(file.api_keys_file.as_ref());
+        let api_keys = ApiKeys::load(api_keys_file.map(PathBuf::as_path), &file.api_keys)?;
+
+        // half a TLS setup must not fall back to plain HTTP unnoticed
+        let tls_cert = args.tls_cert.clone().or(file.tls_cert);
+        let tls_key = args.tls_key.clone().or(file.tls_key);
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err(anyhow!(
+                "`tls_cert` and `tls_key` must be given together, or neither to serve plain HTTP"
+            ));
+        }
+
+        let cache = (!args.no_cache && file.cache.unwrap_or(true)).then(|| {
+            TranscriptCache::new(
+                args.cache_dir
+                    .clone()
+                    .or(file.cache_dir)
+                    .unwrap_or_else(TranscriptCache::default_dir),
+                args.cache_max_size
+                    .or(file.cache_max_size)
+                    .map_or(TranscriptCache::DEFAULT_MAX_SIZE, |mb| mb * 1024 * 1024),
+                args.cache_max_age
+                    .or(file.cache_max_age)
+                    .map_or(TranscriptCache::DEFAULT_MAX_AGE, |days| {
+                        Duration::from_secs(days * 24 * 60 * 60)
+                    }),
+            )
+        });
+
+        #[cfg(unix)]
+        let unix_socket = args.unix_socket.clone().or(file.unix_socket);
+        #[cfg(not(unix))]
+        let unix_socket = file.unix_socket.and(None);
+
+        Ok(Self {
+            host: args
+                .host
+                .or(file.host)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            port: args.port.or(file.port).unwrap_or(8000),
+            tls_cert,
+            tls_key,
+            unix_socket,
+            models,
+            preload: !args.no_preload && file.preload.unwrap_or(true),
+            max_loaded_models: at_least_one(
+                "max_loaded_models",
+                args.max_loaded_models
+                    .or(file.max_loaded_models)
+                    .unwrap_or(2),
+            )?,
+            workers: at_least_one("workers", args.workers.or(file.workers).unwrap_or(1))?,
+            queue_size: args.queue_size.or(file.queue_size).unwrap_or(16),
+            job_ttl: at_least_one("job_ttl", args.job_ttl.or(file.job_ttl).unwrap_or(3600))?,
+            cache,
+            shutdown_timeout: args
+                .shutdown_timeout
+                .or(file.shutdown_timeout)
+                .unwrap_or(30),
+            api_keys,
+            settings: Settings {
+                max_body_size: args.max_body_size.or(file.max_body_size).unwrap_or(25)
+                    * 1024
+                    * 1024,
+                language,
+                prompt: file.prompt,
+                response_format,
+            },
+        })
+    }
+
+    /// Settings that only take effect on restart and differ between `self` and `other`.
+    pub fn restart_required(&self, other: &Self) -> Vec<&'static str> {
+        [
+            ("host", self.host != other.host),
+            ("port", self.port != other.port),
+            ("tls_cert", self.tls_cert != other.tls_cert),
+            ("tls_key", self.tls_key != other.tls_key),
+            ("unix_socket", self.unix_socket != other.unix_socket),
+            ("job_ttl", self.job_ttl != other.job_ttl),
+            ("cache", self.cache != other.cache),
+            (
+                "shutdown_timeout",
+                self.shutdown_timeout != other.shutdown_timeout,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+}
+
+fn at_least_one<T: PartialEq + From<u8>>(name: &str, value: T) -> Result<T> {
+    if value == T::from(0) {
+        return Err(anyhow!("`{name}` must be at least 1"));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(subcommand)]
+        serve: Serve,
+    }
+
+    #[derive(Parser)]
+    enum Serve {
+        Serve(ServeArgs),
+    }
+
+    fn args(flags: &[&str]) -> ServeArgs {
+        let Serve::Serve(args) = Cli::parse_from(["whisper", "serve"].iter().chain(flags)).serve;
+        args
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            port = 9000
+            workers = 4
+            models = ["tiny.en", "medium"]
+            language = "de"
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::merge(&args(&["--port", "9001"]), file).unwrap();
+        assert_eq!(config.port, 9001);
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.queue_size, 16);
+        assert_eq!(config.models, ["tiny.en", "medium"]);
+        assert_eq!(config.settings.language, Some(Language::German));
+
+        assert!(Config::merge(&args(&[]), ConfigFile::default()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let file = |content: &str| -> ConfigFile { toml::from_str(content).unwrap() };
+        let models = r#"models = ["tiny"]"#;
+
+        assert!(Config::merge(
+            &args(&[]),
+            file(&format!("{models}\ntls_cert = \"cert.pem\""))
+        )
+        .is_err());
+        assert!(Config::merge(
+            &args(&[]),
+            file(&format!("{models}\ntls_key = \"key.pem\""))
+        )
+        .is_err());
+        assert!(Config::merge(&args(&["--workers", "0"]), file(models)).is_err());
+        assert!(Config::merge(&args(&[]), file(&format!("{models}\njob_ttl = 0"))).is_err());
+        assert!(Config::merge(&args(&["--max-loaded-models", "0"]), file(models)).is_err());
+
+        let config = Config::merge(
+            &args(&[]),
+            file(&format!(
+                "{models}\ntls_cert = \"cert.pem\"\ntls_key = \"key.pem\""
+            )),
+        )
+        .unwrap();
+        assert_eq!(config.tls_key, Some(PathBuf::from("key.pem")));
+    }
+}
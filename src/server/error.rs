@@ -0,0 +1,183 @@
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use whisper_cli::TranscribeError;
+
+use super::json_response;
+use super::models::ModelError;
+use super::pool::PoolError;
+use super::upload::UploadError;
+
+const INVALID_REQUEST: &str = "invalid_request_error";
+const SERVER_ERROR: &str = "server_error";
+
+/// A failed request, answered with an `OpenAI`-style error body:
+/// `{"error":{"message","type","param","code"}}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    body: ErrorBody,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    message: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// The request field the error is about.
+    param: Option<&'static str>,
+    code: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a ErrorBody,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: ErrorBody {
+                message: message.into(),
+                kind,
+                param: None,
+                code: None,
+            },
+        }
+    }
+
+    /// A 400 caused by the request's content.
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, INVALID_REQUEST, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, INVALID_REQUEST, message)
+    }
+
+    pub fn server_error(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, SERVER_ERROR, message)
+    }
+
+    #[must_use]
+    pub const fn with_param(mut self, param: &'static str) -> Self {
+        self.body.param = Some(param);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_code(mut self, code: &'static str) -> Self {
+        self.body.code = Some(code);
+        self
+    }
+
+    /// Maps a failed transcription to the client's fault when its audio is to blame.
+    pub fn transcription(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<TranscribeError>() {
+            Some(TranscribeError::InvalidAudio) => Self::invalid_request(format!(
+                "{error:#}, the file might be corrupted or unsupported"
+            ))
+            .with_param("file")
+            .with_code("invalid_audio"),
+            Some(TranscribeError::NoSpeech) => {
+                Self::invalid_request("no speech was found in the audio")
+                    .with_param("file")
+                    .with_code("no_speech")
+            }
+            None => Self::server_error(format!("transcription failed: {error:#}")),
+        }
+    }
+
+    /// The error body on its own, for errors sent over an already open stream.
+    pub fn into_body(self) -> ErrorBody {
+        self.body
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let mut response = json_response(self.status, &ErrorResponse { error: &self.body });
+        if self.status == StatusCode::TOO_MANY_REQUESTS {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        response
+    }
+}
+
+impl From<UploadError> for ApiError {
+    fn from(error: UploadError) -> Self {
+        let (status, code) = match error {
+            UploadError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "file_too_large"),
+            UploadError::Unsupported(_) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid_file_format")
+            }
+            UploadError::MissingFile | UploadError::Multipart(_) => {
+                (StatusCode::BAD_REQUEST, "invalid_multipart")
+            }
+            UploadError::Io(_) => return Self::server_error(error.to_string()),
+        };
+
+        Self::new(status, INVALID_REQUEST, error.to_string())
+            .with_param("file")
+            .with_code(code)
+    }
+}
+
+impl From<PoolError> for ApiError {
+    fn from(error: PoolError) -> Self {
+        match error {
+            PoolError::QueueFull => Self::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "requests",
+                "every worker is busy, try again later",
+            )
+            .with_code("rate_limit_exceeded"),
+            PoolError::Unavailable => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                SERVER_ERROR,
+                "the transcription workers are unavailable",
+            ),
+        }
+    }
+}
+
+impl From<ModelError> for ApiError {
+    fn from(error: ModelError) -> Self {
+        match error {
+            ModelError::NotFound(_) => Self::not_found(error.to_string())
+                .with_param("model")
+                .with_code("model_not_found"),
+            ModelError::Load(_) => Self::server_error(error.to_string()),
+            ModelError::Closed => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                SERVER_ERROR,
+                error.to_string(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_transcription_error() {
+        let corrupt = Err::<(), _>(anyhow!("unable to convert file"))
+            .context(TranscribeError::InvalidAudio)
+            .unwrap_err();
+        let error = ApiError::transcription(&corrupt);
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.body.code, Some("invalid_audio"));
+
+        let silent = ApiError::transcription(&TranscribeError::NoSpeech.into());
+        assert_eq!(silent.body.code, Some("no_speech"));
+
+        let failed = ApiError::transcription(&anyhow!("failed to transcribe"));
+        assert_eq!(failed.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use tracing::{Instrument, Span};
+use uuid::Uuid;
+
+use whisper_cli::{TranscribeOptions, Transcript};
+
+use super::error::ApiError;
+use super::{
+    check_response_format, json_response, read_transcription_request, transcript_response, AppState,
+};
+
+/// Transcriptions submitted through `/v1/jobs`, kept around until they expire.
+pub struct JobStore {
+    jobs: Mutex<HashMap<Uuid, Arc<Job>>>,
+    /// How long finished jobs are kept before they are forgotten.
+    ttl: Duration,
+}
+
+struct Job {
+    id: Uuid,
+    created_at: u64,
+    options: TranscribeOptions,
+    granularities: Vec<String>,
+    /// Percentage reported by whisper's progress callback.
+    progress: AtomicI32,
+    status: Mutex<JobStatus>,
+}
+
+enum JobStatus {
+    Queued,
+    Running,
+    Done {
+        transcript: Transcript,
+        finished: Instant,
+    },
+    Failed {
+        error: String,
+        finished: Instant,
+    },
+}
+
+impl JobStatus {
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Done { .. } => "done",
+            Self::Failed { .. } => "failed",
+        }
+    }
+
+    const fn finished(&self) -> Option<Instant> {
+        match self {
+            Self::Queued | Self::Running => None,
+            Self::Done { finished, .. } | Self::Failed { finished, .. } => Some(*finished),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JobResponse {
+    id: String,
+    object: &'static str,
+    status: &'static str,
+    progress: i32,
+    created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl JobResponse {
+    fn new(job: &Job) -> Self {
+        let status = job.status.lock().unwrap();
+        Self {
+            id: job.id.to_string(),
+            object: "transcription.job",
+            status: status.name(),
+            progress: job.progress.load(Ordering::Relaxed),
+            created_at: job.created_at,
+            error: match &*status {
+                JobStatus::Failed { error, .. } => Some(error.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub const fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn insert(&self, job: Arc<Job>) {
+        self.jobs.lock().unwrap().insert(job.id, job);
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Job>> {
+        let id = Uuid::parse_str(id).ok()?;
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Forgets finished jobs older than the TTL.
+    pub fn remove_expired(&self) {
+        self.jobs.lock().unwrap().retain(|_, job| {
+            job.status
+                .lock()
+                .unwrap()
+                .finished()
+                .is_none_or(|finished| finished.elapsed() < self.ttl)
+        });
+    }
+}
+
+/// Routes `/v1/jobs/{id}` and `/v1/jobs/{id}/result`, given the path after `/v1/jobs/`.
+pub fn handle_job(
+    req: &Request<Body>,
+    state: &AppState,
+    path: &str,
+) -> Result<Response<Body>, ApiError> {
+    let (id, result) = match path.split_once('/') {
+        Some((id, "result")) => (id, true),
+        Some(_) => return Err(ApiError::not_found(format!("no route for /v1/jobs/{path}"))),
+        None => (path, false),
+    };
+    let job = state.jobs.get(id).ok_or_else(|| {
+        ApiError::not_found(format!("no job with id {id}")).with_code("job_not_found")
+    })?;
+
+    if !result {
+        return Ok(json_response(StatusCode::OK, &JobResponse::new(&job)));
+    }
+
+    let settings = state.settings();
+    let format = req
+        .uri()
+        .query()
+        .and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find_map(|(key, value)| (key == "format").then_some(value))
+        })
+        .unwrap_or(&settings.response_format);
+    check_response_format(format, "format")?;
+
+    let status = job.status.lock().unwrap();
+    match &*status {
+        JobStatus::Done { transcript, .. } => Ok(transcript_response(
+            transcript,
+            format,
+            &job.options,
+            &job.granularities,
+        )),
+        status => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "invalid_request_error",
+            format!("job {id} is {}, it has no result", status.name()),
+        )
+        .with_code("job_not_done")),
+    }
+}
+
+/// Accepts an upload as a background job and answers right away with its id.
+pub async fn handle_create_job(
+    req: Request<Body>,
+    state: Arc<AppState>,
+) -> Result<Response<Body>, ApiError> {
+    let mut request = read_transcription_request(req, &state).await?;
+    request.options.translate = request.fields.get("task").is_some_and(|t| t == "translate");
+
+    let pool = state.models.get(request.model.as_deref()).await?;
+
+    let job = Arc::new(Job {
+        id: Uuid::new_v4(),
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        options: request.options.clone(),
+        granularities: request.granularities.clone(),
+        progress: AtomicI32::new(0),
+        status: Mutex::new(JobStatus::Queued),
+    });
+
+    let worker_job = Arc::clone(&job);
+    let result = pool.submit(move |whisper, state| {
+        *worker_job.status.lock().unwrap() = JobStatus::Running;
+        let progress_job = Arc::clone(&worker_job);
+        whisper.transcribe_with_state(state, &request.audio, &request.options, move |p| {
+            progress_job.progress.store(p, Ordering::Relaxed);
+        })
+    });
+    let result = result?;
+
+    state.jobs.insert(Arc::clone(&job));
+    tracing::info!(job = %job.id, "queued the job");
+
+    let finished_job = Arc::clone(&job);
+    let metrics = Arc::clone(&state.metrics);
+    let finishing = async move {
+        let status = match result.await {
+            Ok(Ok(transcript)) => {
+                metrics.record_transcript(&transcript);
+                finished_job.progress.store(100, Ordering::Relaxed);
+                JobStatus::Done {
+                    transcript,
+                    finished: Instant::now(),
+                }
+            }
+            Ok(Err(e)) => {
+                tracing::warn!(job = %finished_job.id, "job failed: {e:#}");
+                JobStatus::Failed {
+                    error: format!("{e:#}"),
+                    finished: Instant::now(),
+                }
+            }
+            Err(_) => JobStatus::Failed {
+                error: "transcription was aborted".to_string(),
+                finished: Instant::now(),
+            },
+        };
+        *finished_job.status.lock().unwrap() = status;
+    };
+    tokio::spawn(finishing.instrument(Span::current()));
+
+    Ok(json_response(StatusCode::ACCEPTED, &JobResponse::new(&job)))
+}
@@ -0,0 +1,145 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::stream;
+use hyper::server::accept::{self, Accept};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client gets to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts TLS connections on `addr`, using the PEM encoded certificate chain and key.
+///
+/// Handshakes run on their own tasks, so a slow client doesn't hold up the others.
+pub async fn tls(
+    addr: SocketAddr,
+    cert: &Path,
+    key: &Path,
+) -> Result<impl Accept<Conn = TlsStream<TcpStream>, Error = io::Error>> {
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(cert, key)?));
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind {addr}"))?;
+
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    // usually out of file descriptors, give some connections time to close
+                    tracing::warn!("failed to accept connection: {e}");
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!(%peer, "TLS handshake failed: {e}"),
+                    Err(_) => tracing::debug!(%peer, "TLS handshake timed out"),
+                }
+            });
+        }
+    });
+
+    Ok(accept::from_stream(stream::unfold(
+        rx,
+        |mut rx| async move {
+            let connection = rx.recv().await?;
+            Some((connection, rx))
+        },
+    )))
+}
+
+fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert).with_context(|| format!("failed to open {}", cert.display()))?,
+    ))
+    .with_context(|| format!("failed to read certificates from {}", cert.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {}", cert.display()));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(
+        File::open(key).with_context(|| format!("failed to open {}", key.display()))?,
+    ))
+    .with_context(|| format!("failed to read the private key from {}", key.display()))?
+    .into_iter()
+    .find_map(|item| match item {
+        rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+        _ => None,
+    })
+    .ok_or_else(|| anyhow!("no private key found in {}", key.display()))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(Certificate).collect(), key)
+        .context("invalid certificate or key")?;
+    // WebSocket upgrades need HTTP/1.1
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Accepts connections on a Unix domain socket at `path`.
+///
+/// The socket is only accessible to its owner and group, so who may connect is
+/// controlled by the file permissions of the socket and its directory.
+#[cfg(unix)]
+pub fn unix(path: &Path) -> Result<impl Accept<Conn = tokio::net::UnixStream, Error = io::Error>> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "another server is listening on {} already",
+                path.display()
+            ));
+        }
+        // left behind by a previous run, it would make binding fail
+        fs::remove_file(path)?;
+    }
+
+    // bound in a directory only we can enter and moved in place once its permissions
+    // are set, so it is never reachable with the wider ones of the umask
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let staging = tempfile::Builder::new()
+        .prefix(".whisper-")
+        .tempdir_in(parent)
+        .with_context(|| format!("failed to create a directory in {}", parent.display()))?;
+    let staged = staging.path().join("socket");
+    let listener = tokio::net::UnixListener::bind(&staged)
+        .with_context(|| format!("failed to bind {}", path.display()))?;
+    fs::set_permissions(&staged, fs::Permissions::from_mode(0o660))?;
+    fs::rename(&staged, path).with_context(|| format!("failed to bind {}", path.display()))?;
+
+    Ok(accept::from_stream(stream::unfold(
+        listener,
+        |listener| async move {
+            let connection = listener.accept().await.map(|(stream, _)| stream);
+            Some((connection, listener))
+        },
+    )))
+}
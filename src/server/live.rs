@@ -0,0 +1,327 @@
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::to_string;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::{Instrument, Span};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Filter;
+
+use whisper_cli::{Language, LiveTranscriber, LiveUpdate, TranscribeOptions, SAMPLE_RATE};
+
+use super::pool::{PoolError, WorkerPool};
+use super::AppState;
+
+/// Longest window whisper can look at in one go.
+const MAX_LENGTH_MS: u64 = 30_000;
+
+/// Chunks of decoded audio waiting for the session. Once full, the socket is no longer
+/// read, which slows down clients sending faster than they are transcribed.
+const CHANNEL_CHUNKS: usize = 64;
+
+#[derive(Deserialize)]
+struct LiveParams {
+    /// `pcm` for raw 16 kHz mono signed 16-bit little-endian samples, anything else
+    /// (e.g. `webm`, `ogg`) is decoded through ffmpeg.
+    #[serde(default = "default_format")]
+    format: String,
+    #[serde(default)]
+    translate: bool,
+    /// ISO-639-1 code of the spoken language, detected when missing.
+    language: Option<String>,
+    /// Name of the model to transcribe with, the default one when missing.
+    model: Option<String>,
+    /// How often the current window is transcribed again.
+    #[serde(default = "default_step_ms")]
+    step_ms: u64,
+    /// How much audio a window holds before its text is final.
+    #[serde(default = "default_length_ms")]
+    length_ms: u64,
+}
+
+impl LiveParams {
+    fn options(&self) -> Result<TranscribeOptions, String> {
+        Ok(TranscribeOptions {
+            translate: self.translate,
+            language: self
+                .language
+                .as_deref()
+                .map(str::parse::<Language>)
+                .transpose()?,
+            ..TranscribeOptions::default()
+        })
+    }
+}
+
+fn default_format() -> String {
+    "pcm".to_string()
+}
+
+const fn default_step_ms() -> u64 {
+    3000
+}
+
+const fn default_length_ms() -> u64 {
+    10000
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum LiveEvent {
+    Partial { text: String },
+    Final { text: String },
+    Error { message: String },
+}
+
+impl LiveEvent {
+    fn error(message: impl Into<String>) -> Self {
+        Self::Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl From<LiveUpdate> for LiveEvent {
+    fn from(update: LiveUpdate) -> Self {
+        match update {
+            LiveUpdate::Partial(text) => Self::Partial { text },
+            LiveUpdate::Final(text) => Self::Final { text },
+        }
+    }
+}
+
+/// Upgrades the request to a WebSocket that transcribes the audio sent over it.
+///
+/// Clients send audio as binary messages and receive `partial` and `final` transcripts
+/// as JSON text messages. Closing the socket, or sending the text `stop`, flushes the
+/// last window.
+pub async fn handle_live(req: Request<Body>, state: Arc<AppState>) -> Response<Body> {
+    // the session outlives the request, but keeps logging under its id
+    let span = Span::current();
+    let filter =
+        warp::ws()
+            .and(warp::query::<LiveParams>())
+            .map(move |ws: Ws, params: LiveParams| {
+                let state = Arc::clone(&state);
+                let span = span.clone();
+                ws.on_upgrade(move |socket| live_session(socket, state, params).instrument(span))
+            });
+
+    match warp::service(filter).call(req).await {
+        Ok(response) => response,
+        Err(e) => match e {},
+    }
+}
+
+async fn live_session(socket: WebSocket, state: Arc<AppState>, params: LiveParams) {
+    let (mut sink, mut stream) = socket.split();
+
+    let pool = match state.models.get(params.model.as_deref()).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            send(&mut sink, LiveEvent::error(e.to_string())).await;
+            return;
+        }
+    };
+
+    let options = match params.options() {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            send(&mut sink, LiveEvent::error(e)).await;
+            return;
+        }
+    };
+
+    let (samples_tx, mut samples_rx) = mpsc::channel::<Vec<f32>>(CHANNEL_CHUNKS);
+    let reader = if params.format == "pcm" {
+        tokio::spawn(async move {
+            let mut pcm = PcmDecoder::default();
+            while let Some(Ok(message)) = stream.next().await {
+                if is_stop(&message) {
+                    break;
+                }
+                if !message.is_binary() {
+                    continue;
+                }
+                if samples_tx
+                    .send(pcm.decode(message.as_bytes()))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        })
+    } else {
+        let ffmpeg = match spawn_ffmpeg(samples_tx) {
+            Ok(ffmpeg) => ffmpeg,
+            Err(e) => {
+                send(&mut sink, LiveEvent::error(format!("ffmpeg failed: {e}"))).await;
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            let mut ffmpeg = ffmpeg;
+            while let Some(Ok(message)) = stream.next().await {
+                if is_stop(&message) {
+                    break;
+                }
+                if message.is_binary() && ffmpeg.write_all(message.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            // closing stdin lets ffmpeg flush the remaining audio
+        })
+    };
+
+    let step = Duration::from_millis(params.step_ms.clamp(100, MAX_LENGTH_MS));
+    let length = Duration::from_millis(params.length_ms.clamp(100, MAX_LENGTH_MS));
+    let live = Arc::new(Mutex::new(LiveTranscriber::new(step, length)));
+
+    while let Some(samples) = samples_rx.recv().await {
+        if !catch_up(&live, &samples, &mut samples_rx) {
+            continue;
+        }
+
+        let window = Arc::clone(&live);
+        let window_options = Arc::clone(&options);
+        let update = pool
+            .run(move |whisper, state| {
+                let mut window = window.lock().unwrap();
+                window.transcribe(whisper, state, &window_options)
+            })
+            .await;
+        match update {
+            Ok(Ok(update)) => {
+                if !send(&mut sink, update.into()).await {
+                    break;
+                }
+            }
+            Ok(Err(e)) => {
+                send(&mut sink, LiveEvent::error(e.to_string())).await;
+                break;
+            }
+            // the audio stays buffered, try again with the next chunk
+            Err(PoolError::QueueFull) => {}
+            Err(PoolError::Unavailable) => {
+                send(&mut sink, LiveEvent::error("service unavailable")).await;
+                break;
+            }
+        }
+    }
+
+    reader.abort();
+    if let Ok(Ok(Some(update))) = finish(&pool, live, options).await {
+        send(&mut sink, update.into()).await;
+    }
+    let _ = sink.close().await;
+}
+
+/// Pushes `samples` and everything else that arrived while the previous step ran,
+/// returning whether the window is due to be transcribed again.
+fn catch_up(
+    live: &Mutex<LiveTranscriber>,
+    samples: &[f32],
+    samples_rx: &mut Receiver<Vec<f32>>,
+) -> bool {
+    let mut live = live.lock().unwrap();
+    let mut dropped = live.push(samples);
+    while let Ok(samples) = samples_rx.try_recv() {
+        dropped += live.push(&samples);
+    }
+    if dropped > 0 {
+        tracing::warn!(
+            "dropped {}ms of audio that arrived faster than it was transcribed",
+            dropped as u64 * 1000 / u64::from(SAMPLE_RATE)
+        );
+    }
+    live.is_ready()
+}
+
+async fn finish(
+    pool: &WorkerPool,
+    live: Arc<Mutex<LiveTranscriber>>,
+    options: Arc<TranscribeOptions>,
+) -> Result<anyhow::Result<Option<LiveUpdate>>, PoolError> {
+    pool.run(move |whisper, state| live.lock().unwrap().finish(whisper, state, &options))
+        .await
+}
+
+/// Sends `event`, returning whether the client is still there.
+async fn send<S>(sink: &mut S, event: LiveEvent) -> bool
+where
+    S: SinkExt<Message> + Unpin,
+{
+    let text = to_string(&event).expect("Failed to serialize to JSON");
+    sink.send(Message::text(text)).await.is_ok()
+}
+
+fn is_stop(message: &Message) -> bool {
+    message.is_close() || message.to_str() == Ok("stop")
+}
+
+/// Turns raw signed 16-bit little-endian bytes into samples, carrying odd bytes over
+/// to the next chunk.
+#[derive(Default)]
+struct PcmDecoder {
+    carry: Option<u8>,
+}
+
+impl PcmDecoder {
+    fn decode(&mut self, bytes: &[u8]) -> Vec<f32> {
+        let mut data = Vec::with_capacity(bytes.len() + 1);
+        data.extend(self.carry.take());
+        data.extend_from_slice(bytes);
+
+        let chunks = data.chunks_exact(2);
+        self.carry = chunks.remainder().first().copied();
+        chunks
+            .map(|pair| f32::from(i16::from_le_bytes([pair[0], pair[1]])) / 32768.0)
+            .collect()
+    }
+}
+
+/// Starts ffmpeg converting whatever is written to its stdin into samples sent to `samples`.
+fn spawn_ffmpeg(samples: Sender<Vec<f32>>) -> std::io::Result<tokio::process::ChildStdin> {
+    let mut child = Command::new("ffmpeg")
+        .args([
+            "-i",
+            "pipe:0",
+            "-f",
+            "s16le",
+            "-ar",
+            "16000",
+            "-ac",
+            "1",
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "pipe:1",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    tokio::spawn(async move {
+        let mut pcm = PcmDecoder::default();
+        let mut buf = vec![0; 32 * 1024];
+        while let Ok(n) = stdout.read(&mut buf).await {
+            if n == 0 || samples.send(pcm.decode(&buf[..n])).await.is_err() {
+                break;
+            }
+        }
+        let _ = child.wait().await;
+    });
+
+    Ok(stdin)
+}
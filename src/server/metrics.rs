@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use hyper::StatusCode;
+
+use whisper_cli::Transcript;
+
+use super::models::ModelRegistry;
+use super::{
+    HEALTH_PATH, JOBS_PATH, LIVE_PATH, METRICS_PATH, MODELS_PATH, READY_PATH, TRANSCRIPTIONS_PATH,
+    TRANSLATIONS_PATH,
+};
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE_METRICS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Seconds spent transcribing a request.
+const PROCESSING_BUCKETS: &[f64] = &[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// Processing time divided by audio length, below 1 is faster than real time.
+const REAL_TIME_FACTOR_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 5.0];
+
+/// Response format a transcription was answered with, attached to the response so
+/// requests can be counted by it.
+#[derive(Clone, Copy)]
+pub struct ResponseFormat(pub &'static str);
+
+/// Counters for the `/metrics` endpoint, in the Prometheus text format.
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Request counts by path, status and response format.
+    requests: BTreeMap<(&'static str, u16, &'static str), u64>,
+    audio_seconds: f64,
+    processing_seconds: Histogram,
+    real_time_factor: Histogram,
+}
+
+struct Histogram {
+    buckets: &'static [f64],
+    /// Observations per bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.buckets.iter().position(|le| value <= *le) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                processing_seconds: Histogram::new(PROCESSING_BUCKETS),
+                real_time_factor: Histogram::new(REAL_TIME_FACTOR_BUCKETS),
+                ..Inner::default()
+            }),
+        }
+    }
+
+    pub fn record_request(&self, path: &str, status: StatusCode, format: Option<ResponseFormat>) {
+        let key = (
+            path_label(path),
+            status.as_u16(),
+            format.map_or("none", |format| format.0),
+        );
+        *self.inner.lock().unwrap().requests.entry(key).or_default() += 1;
+    }
+
+    /// Accounts for a finished transcription.
+    pub fn record_transcript(&self, transcript: &Transcript) {
+        let processing = transcript.processing_time.as_secs_f64();
+        let audio = transcript.duration.as_secs_f64();
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.audio_seconds += audio;
+        inner.processing_seconds.observe(processing);
+        if audio > 0.0 {
+            inner.real_time_factor.observe(processing / audio);
+        }
+    }
+
+    pub fn render(&self, models: &ModelRegistry) -> String {
+        let mut out = String::new();
+        let inner = self.inner.lock().unwrap();
+
+        out.push_str(
+            "# HELP whisper_requests_total HTTP requests by path, status and response format.\n",
+        );
+        out.push_str("# TYPE whisper_requests_total counter\n");
+        for ((path, status, format), count) in &inner.requests {
+            let _ = writeln!(
+                out,
+                "whisper_requests_total{{path=\"{path}\",status=\"{status}\",format=\"{format}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP whisper_audio_seconds_total Seconds of audio transcribed.\n");
+        out.push_str("# TYPE whisper_audio_seconds_total counter\n");
+        let _ = writeln!(out, "whisper_audio_seconds_total {}", inner.audio_seconds);
+
+        inner.processing_seconds.render(
+            &mut out,
+            "whisper_processing_seconds",
+            "Time spent transcribing a request.",
+        );
+        inner.real_time_factor.render(
+            &mut out,
+            "whisper_real_time_factor",
+            "Processing time divided by audio length.",
+        );
+        drop(inner);
+
+        let stats = models.stats();
+        out.push_str("# HELP whisper_model_loaded Whether the model is in memory.\n");
+        out.push_str("# TYPE whisper_model_loaded gauge\n");
+        for model in &stats {
+            let _ = writeln!(
+                out,
+                "whisper_model_loaded{{model=\"{}\"}} {}",
+                model.id,
+                u8::from(model.queued.is_some())
+            );
+        }
+        out.push_str("# HELP whisper_queue_depth Requests waiting for a free worker.\n");
+        out.push_str("# TYPE whisper_queue_depth gauge\n");
+        for model in &stats {
+            if let Some(queued) = model.queued {
+                let _ = writeln!(
+                    out,
+                    "whisper_queue_depth{{model=\"{}\"}} {queued}",
+                    model.id
+                );
+            }
+        }
+        out.push_str("# HELP whisper_model_load_seconds Time the last load of the model took.\n");
+        out.push_str("# TYPE whisper_model_load_seconds gauge\n");
+        for model in &stats {
+            if let Some(load_seconds) = model.load_seconds {
+                let _ = writeln!(
+                    out,
+                    "whisper_model_load_seconds{{model=\"{}\"}} {load_seconds}",
+                    model.id
+                );
+            }
+        }
+
+        out
+    }
+}
+
+/// Maps a request path to one of the routes, keeping the label's cardinality bounded.
+fn path_label(path: &str) -> &'static str {
+    match path {
+        TRANSCRIPTIONS_PATH => TRANSCRIPTIONS_PATH,
+        TRANSLATIONS_PATH => TRANSLATIONS_PATH,
+        MODELS_PATH => MODELS_PATH,
+        LIVE_PATH => LIVE_PATH,
+        HEALTH_PATH => HEALTH_PATH,
+        READY_PATH => READY_PATH,
+        METRICS_PATH => METRICS_PATH,
+        JOBS_PATH => JOBS_PATH,
+        path if path.starts_with("/v1/jobs/") && path.ends_with("/result") => {
+            "/v1/jobs/{id}/result"
+        }
+        path if path.starts_with("/v1/jobs/") => "/v1/jobs/{id}",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+        histogram.observe(10.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test", "A test.");
+        assert!(out.contains("test_bucket{le=\"1\"} 1\n"));
+        assert!(out.contains("test_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("test_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_sum 13.5\n"));
+    }
+}
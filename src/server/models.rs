@@ -0,0 +1,465 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Instant, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+
+use whisper_cli::{Language, Model, Size, TranscriptCache, Whisper};
+
+use super::pool::WorkerPool;
+
+/// Model name `OpenAI` clients send by default, served by the default model.
+const OPENAI_MODEL: &str = "whisper-1";
+
+#[derive(Debug)]
+pub enum ModelError {
+    /// No model is served under the requested name.
+    NotFound(String),
+    /// The model file could not be loaded, or its workers could not start.
+    Load(String),
+    /// The server is shutting down.
+    Closed,
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "the model `{id}` does not exist"),
+            Self::Load(e) => write!(f, "failed to load the model: {e}"),
+            Self::Closed => write!(f, "the server is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+/// The models a server offers, loaded on first use.
+///
+/// At most `max_loaded` models stay in memory, once more are needed the least recently
+/// used one is unloaded. Requests still running on it finish first, its workers exit
+/// once the last of them dropped the pool.
+///
+/// The list and the limits can be replaced with [`update`](Self::update) while serving.
+pub struct ModelRegistry {
+    /// The first model is the default one.
+    models: RwLock<Vec<Arc<ModelEntry>>>,
+    workers: AtomicUsize,
+    queue_size: AtomicUsize,
+    max_loaded: AtomicUsize,
+    /// Handed to every model loaded, see [`Whisper::with_cache`].
+    cache: Option<Arc<TranscriptCache>>,
+    /// Models that were unloaded or are no longer served, while requests that got them
+    /// before may still run or queue jobs. Shutdown closes and waits for them too.
+    retired: std::sync::Mutex<Vec<Weak<WorkerPool>>>,
+    /// Incremented on every use, ordering the models by recency.
+    clock: AtomicU64,
+    /// Set once the default model has been loaded at startup.
+    ready: AtomicBool,
+    /// Set once the server is shutting down, no more work is accepted.
+    closed: AtomicBool,
+}
+
+struct ModelEntry {
+    /// Name the model is listed and selected under.
+    id: String,
+    path: PathBuf,
+    /// Set for models named by size, which are downloaded when missing.
+    size: Option<Size>,
+    /// Unix timestamp of the model file, reported as `created`.
+    created: u64,
+    pool: Mutex<Option<Arc<WorkerPool>>>,
+    last_used: AtomicU64,
+    /// Milliseconds the last load took, 0 until loaded once.
+    load_millis: AtomicU64,
+}
+
+/// What `/metrics` reports about a model.
+pub struct ModelStats {
+    pub id: String,
+    /// Jobs waiting for a worker, `None` while the model is not loaded.
+    pub queued: Option<usize>,
+    pub load_seconds: Option<f64>,
+}
+
+impl ModelEntry {
+    /// Resolves a model given as a path, or as a name from the download cache.
+    fn new(model: &str) -> Self {
+        let size = Size::from_str(model, false).ok();
+        let (id, path) = size.map_or_else(
+            || {
+                let path = PathBuf::from(model);
+                let id = path
+                    .file_stem()
+                    .map_or_else(|| model.to_string(), |s| s.to_string_lossy().into_owned());
+                (id, path)
+            },
+            |size| (size.to_string(), size.get_path()),
+        );
+
+        Self {
+            id,
+            created: modified(&path),
+            path,
+            size,
+            pool: Mutex::new(None),
+            last_used: AtomicU64::new(0),
+            load_millis: AtomicU64::new(0),
+        }
+    }
+}
+
+fn modified(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+impl ModelRegistry {
+    pub fn new(models: &[String], workers: usize, queue_size: usize, max_loaded: usize) -> Self {
+        let registry = Self {
+            models: RwLock::default(),
+            workers: AtomicUsize::new(0),
+            queue_size: AtomicUsize::new(0),
+            max_loaded: AtomicUsize::new(0),
+            cache: None,
+            retired: std::sync::Mutex::default(),
+            clock: AtomicU64::new(0),
+            ready: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        };
+        registry.replace(models, workers, queue_size, max_loaded);
+        registry
+    }
+
+    #[must_use]
+    pub fn with_cache(mut self, cache: Option<TranscriptCache>) -> Self {
+        self.cache = cache.map(Arc::new);
+        self
+    }
+
+    /// Replaces the served models and limits. Models that are still served under the
+    /// same name and path keep their loaded workers, removed ones finish their running
+    /// requests first. When the workers or the queue size change, loaded models are
+    /// loaded again with the new ones, see [`reload`](Self::reload).
+    pub async fn update(
+        &self,
+        models: &[String],
+        workers: usize,
+        queue_size: usize,
+        max_loaded: usize,
+    ) {
+        let resized = self.workers.load(Ordering::Relaxed) != workers
+            || self.queue_size.load(Ordering::Relaxed) != queue_size;
+        for model in self.replace(models, workers, queue_size, max_loaded) {
+            // waits for a load in progress, `get` retires what it loads for removed models
+            let pool = model.pool.lock().await.take();
+            if let Some(pool) = pool {
+                self.retire(&pool);
+            }
+        }
+
+        if resized {
+            for model in self.entries() {
+                self.reload(&model).await;
+            }
+        }
+    }
+
+    /// Loads `model` again if it is loaded, and swaps the new workers in once they are
+    /// up. Requests keep using the old ones meanwhile, which finish what they got.
+    async fn reload(&self, model: &ModelEntry) {
+        if model.pool.lock().await.is_none() {
+            return;
+        }
+
+        let loaded = match self.load(model).await {
+            Ok(loaded) => Arc::new(loaded),
+            Err(e) => {
+                tracing::error!(model = model.id, "failed to reload the model: {e}");
+                return;
+            }
+        };
+        if self.closed.load(Ordering::Relaxed) {
+            loaded.close();
+        }
+
+        // a model unloaded meanwhile stays unloaded, dropping `loaded` stops its workers
+        let mut pool = model.pool.lock().await;
+        let Some(old) = pool.as_mut() else {
+            return;
+        };
+        let old = std::mem::replace(old, loaded);
+        drop(pool);
+        self.retire(&old);
+        tracing::info!(
+            model = model.id,
+            workers = self.workers.load(Ordering::Relaxed),
+            "reloaded the model"
+        );
+    }
+
+    /// Swaps in the new models and limits, returning the models no longer served.
+    fn replace(
+        &self,
+        models: &[String],
+        workers: usize,
+        queue_size: usize,
+        max_loaded: usize,
+    ) -> Vec<Arc<ModelEntry>> {
+        self.workers.store(workers, Ordering::Relaxed);
+        self.queue_size.store(queue_size, Ordering::Relaxed);
+        self.max_loaded.store(max_loaded.max(1), Ordering::Relaxed);
+
+        let mut entries = self.models.write().unwrap();
+        let replaced: Vec<_> = models
+            .iter()
+            .map(|model| {
+                let entry = ModelEntry::new(model);
+                entries
+                    .iter()
+                    .find(|old| old.id == entry.id && old.path == entry.path)
+                    .map_or_else(|| Arc::new(entry), Arc::clone)
+            })
+            .collect();
+        let old = std::mem::replace(&mut *entries, replaced.clone());
+        drop(entries);
+        old.into_iter()
+            .filter(|model| !replaced.iter().any(|entry| Arc::ptr_eq(entry, model)))
+            .collect()
+    }
+
+    /// Keeps track of `pool` until its last jobs are done, closing it if the server is
+    /// shutting down already.
+    fn retire(&self, pool: &Arc<WorkerPool>) {
+        if self.closed.load(Ordering::Relaxed) {
+            pool.close();
+        }
+        let mut retired = self.retired.lock().unwrap();
+        retired.retain(|pool| pool.upgrade().is_some_and(|pool| !pool.is_idle()));
+        retired.push(Arc::downgrade(pool));
+    }
+
+    fn retired(&self) -> Vec<Arc<WorkerPool>> {
+        self.retired
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
+
+    fn entries(&self) -> Vec<Arc<ModelEntry>> {
+        self.models.read().unwrap().clone()
+    }
+
+    /// Loads the default model, so the first request doesn't wait for it.
+    pub async fn preload(&self) -> Result<(), ModelError> {
+        self.get(None).await?;
+        self.ready.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Reports the server as ready without loading a model, for `--no-preload`.
+    pub fn skip_preload(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Whether any model is in memory.
+    pub fn is_loaded(&self) -> bool {
+        self.entries()
+            .iter()
+            .any(|model| model.pool.try_lock().is_ok_and(|pool| pool.is_some()))
+    }
+
+    /// Names and creation times of the served models.
+    pub fn list(&self) -> Vec<(String, u64)> {
+        self.entries()
+            .iter()
+            .map(|model| (model.id.clone(), model.created))
+            .collect()
+    }
+
+    pub fn stats(&self) -> Vec<ModelStats> {
+        self.entries()
+            .iter()
+            .map(|model| {
+                let load_millis = model.load_millis.load(Ordering::Relaxed);
+                ModelStats {
+                    id: model.id.clone(),
+                    // a model that is loading holds the lock
+                    queued: model
+                        .pool
+                        .try_lock()
+                        .ok()
+                        .and_then(|pool| pool.as_ref().map(|pool| pool.queued())),
+                    #[allow(clippy::cast_precision_loss)]
+                    load_seconds: (load_millis > 0).then(|| load_millis as f64 / 1000.0),
+                }
+            })
+            .collect()
+    }
+
+    /// Stops every model from taking work, see [`WorkerPool::close`]. Waits for models
+    /// that are loading.
+    pub async fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for model in self.entries() {
+            if let Some(pool) = &*model.pool.lock().await {
+                pool.close();
+            }
+        }
+        for pool in self.retired() {
+            pool.close();
+        }
+    }
+
+    /// Whether no model, served or retired, is running or queueing a job.
+    pub async fn is_idle(&self) -> bool {
+        for model in self.entries() {
+            if model
+                .pool
+                .lock()
+                .await
+                .as_ref()
+                .is_some_and(|pool| !pool.is_idle())
+            {
+                return false;
+            }
+        }
+        self.retired().iter().all(|pool| pool.is_idle())
+    }
+
+    /// The workers of the model named `id`, or of the default model when no name or
+    /// `whisper-1` is given. Loads the model first if needed.
+    pub async fn get(&self, id: Option<&str>) -> Result<Arc<WorkerPool>, ModelError> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(ModelError::Closed);
+        }
+
+        // without a name the first model, the default one, matches
+        let id = id.filter(|id| !id.is_empty() && *id != OPENAI_MODEL);
+        let model = self
+            .models
+            .read()
+            .unwrap()
+            .iter()
+            .find(|model| id.is_none_or(|id| model.id == id))
+            .map(Arc::clone)
+            .ok_or_else(|| ModelError::NotFound(id.unwrap_or(OPENAI_MODEL).to_string()))?;
+        model.last_used.store(
+            self.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+
+        // concurrent requests for a model that is loading wait for it here
+        let mut pool = model.pool.lock().await;
+        if let Some(pool) = &*pool {
+            return Ok(Arc::clone(pool));
+        }
+
+        let started = Instant::now();
+        let loaded = Arc::new(self.load(&model).await?);
+        if self.closed.load(Ordering::Relaxed) {
+            // shutdown started while loading, see `close`
+            loaded.close();
+        }
+        model.load_millis.store(
+            u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        *pool = Some(Arc::clone(&loaded));
+        // the model may have been removed by a reload while it was loading
+        if !self
+            .entries()
+            .iter()
+            .any(|entry| Arc::ptr_eq(entry, &model))
+        {
+            pool.take();
+            self.retire(&loaded);
+        }
+        drop(pool);
+        tracing::info!(
+            model = model.id,
+            workers = self.workers.load(Ordering::Relaxed),
+            elapsed = ?started.elapsed(),
+            "loaded the model"
+        );
+
+        self.evict(&model);
+        Ok(loaded)
+    }
+
+    async fn load(&self, model: &ModelEntry) -> Result<WorkerPool, ModelError> {
+        if !model.path.exists() {
+            let Some(size) = model.size else {
+                return Err(ModelError::Load(format!(
+                    "{} not found",
+                    model.path.display()
+                )));
+            };
+
+            tracing::info!(model = %size, "downloading the model");
+            Model::new(size)
+                .download()
+                .await
+                .map_err(|e| ModelError::Load(format!("{e:#}")))?;
+        }
+
+        let path = model.path.clone();
+        let workers = self.workers.load(Ordering::Relaxed);
+        let queue_size = self.queue_size.load(Ordering::Relaxed);
+        let cache = self.cache.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut whisper =
+                Handle::current().block_on(Whisper::from_model_path(&path, Some(Language::Auto)));
+            if let Some(cache) = cache {
+                whisper = whisper.with_cache(cache);
+            }
+            WorkerPool::new(whisper, workers, queue_size)
+        })
+        .await
+        .map_err(|e| ModelError::Load(e.to_string()))?
+        .map_err(|e| ModelError::Load(e.to_string()))
+    }
+
+    /// Unloads the least recently used models until the budget is met, keeping `keep`.
+    fn evict(&self, keep: &Arc<ModelEntry>) {
+        let models = self.entries();
+        loop {
+            // models that are loading hold their lock and are skipped
+            let loaded: Vec<_> = models
+                .iter()
+                .filter_map(|model| {
+                    let pool = model.pool.try_lock().ok()?;
+                    pool.is_some().then_some((model, pool))
+                })
+                .collect();
+            if loaded.len() <= self.max_loaded.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let Some((model, mut pool)) = loaded
+                .into_iter()
+                .filter(|(model, _)| !Arc::ptr_eq(model, keep))
+                .min_by_key(|(model, _)| model.last_used.load(Ordering::Relaxed))
+            else {
+                return;
+            };
+            if let Some(pool) = pool.take() {
+                self.retire(&pool);
+            }
+            tracing::info!(model = model.id, "unloaded the model");
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::oneshot;
+use tracing::Span;
+
+use whisper_cli::{Whisper, WhisperState};
+
+type Job = Box<dyn FnOnce(&Whisper, &mut WhisperState) + Send>;
+
+#[derive(Debug)]
+pub enum PoolError {
+    /// Every worker is busy and the queue has no room left.
+    QueueFull,
+    /// The workers are gone, or dropped the job without answering.
+    Unavailable,
+}
+
+/// Runs transcriptions on dedicated threads, off the async executor.
+///
+/// All workers share one model, each with its own `WhisperState`. Jobs wait in a
+/// bounded queue until a worker picks them up.
+pub struct WorkerPool {
+    queue: SyncSender<Job>,
+    shared: Arc<Shared>,
+}
+
+/// State the pool shares with its workers.
+#[derive(Default)]
+struct Shared {
+    /// Jobs waiting for a worker.
+    queued: AtomicUsize,
+    /// Jobs a worker is running right now.
+    running: AtomicUsize,
+    /// Set once the pool stopped taking work, queued jobs are dropped unanswered.
+    closed: AtomicBool,
+}
+
+impl WorkerPool {
+    pub fn new(whisper: Whisper, workers: usize, queue_size: usize) -> Result<Self> {
+        // without a worker the queue is never read, and every job would fail
+        if workers == 0 {
+            return Err(anyhow!("the worker pool needs at least one worker"));
+        }
+
+        let whisper = Arc::new(whisper);
+        let (queue, jobs) = mpsc::sync_channel::<Job>(queue_size);
+        let jobs = Arc::new(Mutex::new(jobs));
+        let shared = Arc::new(Shared::default());
+
+        for i in 0..workers {
+            let state = whisper.create_state()?;
+            let whisper = Arc::clone(&whisper);
+            let jobs = Arc::clone(&jobs);
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name(format!("whisper-worker-{i}"))
+                .spawn(move || work(&whisper, state, &jobs, &shared))?;
+        }
+
+        Ok(Self { queue, shared })
+    }
+
+    /// Number of jobs waiting for a free worker.
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Ordering::Relaxed)
+    }
+
+    /// Whether no job is queued or running.
+    pub fn is_idle(&self) -> bool {
+        self.queued() == 0 && self.shared.running.load(Ordering::Relaxed) == 0
+    }
+
+    /// Stops taking work. Running jobs finish, queued and new ones fail with
+    /// [`PoolError::Unavailable`].
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+    }
+
+    /// Queues `job` and waits for a worker to run it.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, PoolError>
+    where
+        F: FnOnce(&Whisper, &mut WhisperState) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(job)?.await.map_err(|_| PoolError::Unavailable)
+    }
+
+    /// Queues `job`, returning a channel that receives its result once a worker ran it.
+    pub fn submit<T, F>(&self, job: F) -> Result<oneshot::Receiver<T>, PoolError>
+    where
+        F: FnOnce(&Whisper, &mut WhisperState) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.shared.closed.load(Ordering::Relaxed) {
+            return Err(PoolError::Unavailable);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        // the worker logs in the span of whoever queued the job, e.g. a request
+        let span = Span::current();
+        self.shared.queued.fetch_add(1, Ordering::Relaxed);
+        self.queue
+            .try_send(Box::new(move |whisper, state| {
+                // the requester may have gone away, nobody is left to tell
+                let _ = tx.send(span.in_scope(|| job(whisper, state)));
+            }))
+            .map_err(|e| {
+                self.shared.queued.fetch_sub(1, Ordering::Relaxed);
+                match e {
+                    TrySendError::Full(_) => PoolError::QueueFull,
+                    TrySendError::Disconnected(_) => PoolError::Unavailable,
+                }
+            })?;
+
+        Ok(rx)
+    }
+}
+
+fn work(whisper: &Whisper, mut state: WhisperState, jobs: &Mutex<Receiver<Job>>, shared: &Shared) {
+    loop {
+        // only hold the lock while waiting, so other workers can pick up the next job
+        let job = jobs.lock().expect("job queue poisoned").recv();
+        let Ok(job) = job else {
+            // the pool was dropped
+            return;
+        };
+        // counted as running before it leaves the queue, so the pool never looks idle in between
+        shared.running.fetch_add(1, Ordering::Relaxed);
+        shared.queued.fetch_sub(1, Ordering::Relaxed);
+
+        // dropping the job unanswered fails it with `PoolError::Unavailable`
+        if !shared.closed.load(Ordering::Relaxed) {
+            // a panicking job drops its reply channel, keep the worker around for the next one
+            if panic::catch_unwind(AssertUnwindSafe(|| job(whisper, &mut state))).is_err() {
+                tracing::error!(
+                    "{} recovered from a panic",
+                    thread::current().name().unwrap_or("worker")
+                );
+            }
+        }
+        shared.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
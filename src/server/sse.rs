@@ -0,0 +1,98 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use futures_util::stream;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use serde_json::to_string;
+use tokio::sync::mpsc;
+
+use super::error::{ApiError, ErrorBody};
+use super::metrics::{Metrics, ResponseFormat};
+use super::pool::WorkerPool;
+use super::{to_seconds, TranscriptionRequest};
+
+const CONTENT_TYPE_EVENT_STREAM: &str = "text/event-stream";
+
+/// Events sent to `stream=true` clients, shaped like `OpenAI`'s streaming transcription events.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum StreamEvent {
+    #[serde(rename = "transcript.text.delta")]
+    Delta {
+        delta: String,
+        segment: i32,
+        start: f64,
+        end: f64,
+    },
+    #[serde(rename = "transcript.text.done")]
+    Done { text: String },
+    #[serde(rename = "error")]
+    Error { error: ErrorBody },
+}
+
+impl StreamEvent {
+    fn to_sse(&self) -> String {
+        format!(
+            "data: {}\n\n",
+            to_string(self).expect("Failed to serialize to JSON")
+        )
+    }
+}
+
+/// Transcribes the upload, sending each segment as a Server-Sent Event as soon as
+/// whisper has decoded it.
+pub fn stream_transcription(
+    pool: &WorkerPool,
+    request: TranscriptionRequest,
+    metrics: Arc<Metrics>,
+) -> Result<Response<Body>, ApiError> {
+    let (tx, rx) = mpsc::unbounded_channel::<StreamEvent>();
+
+    let submitted = pool.submit(move |whisper, state| {
+        let segments = tx.clone();
+        let transcript = whisper.transcribe_streaming_with_state(
+            state,
+            &request.audio,
+            &request.options,
+            move |segment| {
+                // the client may have disconnected, the transcript is discarded then
+                let _ = segments.send(StreamEvent::Delta {
+                    delta: segment.text,
+                    segment: segment.segment,
+                    start: to_seconds(segment.start_timestamp),
+                    end: to_seconds(segment.end_timestamp),
+                });
+            },
+        );
+
+        let _ = tx.send(match transcript {
+            Ok(transcript) => {
+                metrics.record_transcript(&transcript);
+                StreamEvent::Done {
+                    text: transcript.as_text(),
+                }
+            }
+            Err(e) => StreamEvent::Error {
+                error: ApiError::transcription(&e).into_body(),
+            },
+        });
+    });
+    submitted?;
+
+    let events = stream::unfold(rx, |mut rx| async move {
+        let event = rx.recv().await?;
+        Some((Ok::<_, Infallible>(event.to_sse()), rx))
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Access-Control-Allow-Origin", "*") // Add this for CORS
+        .header(CONTENT_TYPE, CONTENT_TYPE_EVENT_STREAM)
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(events))
+        .unwrap();
+    response.extensions_mut().insert(ResponseFormat("stream"));
+    Ok(response)
+}
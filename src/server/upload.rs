@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use hyper::Body;
+use multer::{Constraints, Multipart, SizeLimit};
+use tempfile::{Builder, TempPath};
+use tokio::io::AsyncWriteExt;
+
+/// File extensions accepted for uploads, the same formats `OpenAI` accepts.
+const ALLOWED_EXTENSIONS: &[&str] = &[
+    "flac", "m4a", "mp3", "mp4", "mpeg", "mpga", "oga", "ogg", "opus", "wav", "webm",
+];
+
+/// Content types accepted for uploads sent without a recognizable file name.
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "audio/flac",
+    "audio/m4a",
+    "audio/mp4",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/opus",
+    "audio/wav",
+    "audio/wave",
+    "audio/webm",
+    "audio/x-flac",
+    "audio/x-m4a",
+    "audio/x-wav",
+    "video/mp4",
+    "video/mpeg",
+    "video/webm",
+];
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// The body is larger than the configured limit.
+    TooLarge,
+    /// The file is not in one of the allowed formats.
+    Unsupported(String),
+    /// No `file` field was sent.
+    MissingFile,
+    Multipart(multer::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge => write!(f, "request body is too large"),
+            Self::Unsupported(format) => write!(
+                f,
+                "unsupported file format {format}, expected one of {}",
+                ALLOWED_EXTENSIONS.join(", ")
+            ),
+            Self::MissingFile => write!(f, "missing the `file` field"),
+            Self::Multipart(e) => write!(f, "invalid multipart body: {e}"),
+            Self::Io(e) => write!(f, "failed to store the upload: {e}"),
+        }
+    }
+}
+
+impl From<multer::Error> for UploadError {
+    fn from(e: multer::Error) -> Self {
+        match e {
+            multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. } => {
+                Self::TooLarge
+            }
+            e => Self::Multipart(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// Process the request body as multipart/form-data.
+//
+// The `file` field is streamed into a fresh temporary file in `dir`, removed once the
+// returned path is dropped. Other fields are collected as text.
+pub async fn process_multipart(
+    body: Body,
+    boundary: String,
+    max_size: u64,
+    dir: &Path,
+) -> Result<(TempPath, HashMap<String, String>), UploadError> {
+    let constraints = Constraints::new().size_limit(SizeLimit::new().whole_stream(max_size));
+    let mut multipart = Multipart::with_constraints(body, boundary, constraints);
+    let mut audio = None;
+    let mut other_fields = HashMap::new();
+
+    // Iterate over the fields, `next_field` method will return the next field if
+    // available.
+    while let Some(mut field) = multipart.next_field().await? {
+        if field.name() == Some("file") {
+            // Check the format before storing anything.
+            let extension = allowed_extension(field.file_name(), field.content_type())?;
+
+            // The client's file name is never used for the path, only its extension is
+            // kept as a hint for the decoder.
+            let file = Builder::new()
+                .prefix("whisper-upload-")
+                .suffix(&format!(".{extension}"))
+                .tempfile_in(dir)?;
+            let mut writer = tokio::fs::File::from_std(file.reopen()?);
+            while let Some(field_chunk) = field.chunk().await? {
+                writer.write_all(&field_chunk).await?;
+            }
+            writer.flush().await?;
+            audio = Some(file.into_temp_path());
+        } else {
+            let mut value = String::new();
+            while let Some(field_chunk) = field.chunk().await? {
+                value.push_str(&String::from_utf8_lossy(&field_chunk));
+            }
+            if let Some(field_name) = field.name() {
+                // Array fields such as `timestamp_granularities[]` repeat, keep every value.
+                other_fields
+                    .entry(field_name.to_string())
+                    .and_modify(|v: &mut String| {
+                        v.push(',');
+                        v.push_str(&value);
+                    })
+                    .or_insert(value);
+            }
+        }
+    }
+
+    let audio = audio.ok_or(UploadError::MissingFile)?;
+    Ok((audio, other_fields))
+}
+
+/// Picks the extension to store an upload with, if its name or content type is allowed.
+fn allowed_extension(
+    file_name: Option<&str>,
+    content_type: Option<&mime::Mime>,
+) -> Result<&'static str, UploadError> {
+    let extension = file_name
+        .and_then(|name| Path::new(name).extension())
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+
+    if let Some(allowed) = extension
+        .as_deref()
+        .and_then(|ext| ALLOWED_EXTENSIONS.iter().find(|allowed| **allowed == ext))
+    {
+        return Ok(allowed);
+    }
+
+    let essence = content_type.map(mime::Mime::essence_str);
+    if essence.is_some_and(|essence| ALLOWED_CONTENT_TYPES.contains(&essence)) {
+        // ffmpeg sniffs the actual format, the extension doesn't matter
+        return Ok("audio");
+    }
+
+    Err(UploadError::Unsupported(
+        extension
+            .or_else(|| essence.map(ToString::to_string))
+            .unwrap_or_else(|| "unknown".to_string()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_extension() {
+        assert_eq!(allowed_extension(Some("talk.MP3"), None).unwrap(), "mp3");
+        assert_eq!(
+            allowed_extension(Some("../../etc/passwd.wav"), None).unwrap(),
+            "wav"
+        );
+        assert_eq!(
+            allowed_extension(Some("blob"), Some(&"audio/ogg".parse().unwrap())).unwrap(),
+            "audio"
+        );
+        assert!(allowed_extension(Some("script.sh"), None).is_err());
+        assert!(allowed_extension(None, None).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::Instrument;
+
+use whisper_cli::{Language, Size, TranscribeOptions, SAMPLE_RATE};
+
+use super::models::ModelRegistry;
+use super::shutdown_signal;
+use crate::WyomingArgs;
+
+/// Version of the Wyoming protocol spoken.
+const PROTOCOL_VERSION: &str = "1.5.2";
+/// Longest header line accepted.
+const MAX_HEADER_LENGTH: u64 = 64 * 1024;
+/// Largest data or payload block accepted with a single event.
+const MAX_BLOCK_LENGTH: usize = 4 * 1024 * 1024;
+/// Longest audio accepted for one transcription, in seconds.
+const MAX_AUDIO_SECONDS: u32 = 10 * 60;
+
+/// Serves speech-to-text over the Wyoming protocol used by Home Assistant: events are
+/// JSON header lines, optionally followed by extra JSON data and a binary payload.
+pub async fn start_wyoming(args: WyomingArgs) -> Result<()> {
+    let models = Arc::new(ModelRegistry::new(
+        &args.model_path,
+        args.workers,
+        args.queue_size,
+        args.max_loaded_models,
+    ));
+    models.preload().await?;
+
+    let addr = SocketAddr::new(args.host, args.port);
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind {addr}"))?;
+    tracing::info!("listening on tcp://{addr}");
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            () = &mut shutdown => break,
+            accepted = listener.accept() => match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::warn!("failed to accept connection: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let models = Arc::clone(&models);
+        let span = tracing::info_span!("wyoming", %peer);
+        tokio::spawn(
+            async move {
+                if let Err(e) = session(stream, &models, args.language).await {
+                    tracing::warn!("closing the connection: {e:#}");
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    tracing::info!("shutting down");
+    models.close().await;
+    Ok(())
+}
+
+/// A decoded event. Data sent inline and in a separate block is merged.
+#[derive(Debug)]
+struct Event {
+    kind: String,
+    data: Map<String, Value>,
+    payload: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: Option<Map<String, Value>>,
+    #[serde(default)]
+    data_length: usize,
+    #[serde(default)]
+    payload_length: usize,
+}
+
+/// Format of `audio-start` and `audio-chunk` events.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+struct AudioFormat {
+    rate: u32,
+    /// Bytes per sample.
+    width: u16,
+    channels: u16,
+}
+
+impl AudioFormat {
+    /// Mixes little-endian PCM `bytes` down to mono samples, at the format's rate.
+    fn decode(self, bytes: &[u8]) -> Result<Vec<f32>> {
+        let width = usize::from(self.width);
+        let channels = usize::from(self.channels);
+        if channels == 0 || self.rate == 0 {
+            bail!("audio needs a sample rate and at least one channel");
+        }
+        let sample = |bytes: &[u8]| match bytes {
+            [b] => f32::from(i16::from(*b) - 128) / 128.0,
+            [lo, hi] => f32::from(i16::from_le_bytes([*lo, *hi])) / 32768.0,
+            #[allow(clippy::cast_precision_loss)]
+            [a, b, c, d] => i32::from_le_bytes([*a, *b, *c, *d]) as f32 / 2_147_483_648.0,
+            _ => 0.0,
+        };
+        if ![1, 2, 4].contains(&width) {
+            bail!("unsupported sample width {width}, expected 1, 2 or 4 bytes");
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let samples = bytes
+            .chunks_exact(width * channels)
+            .map(|frame| frame.chunks_exact(width).map(sample).sum::<f32>() / channels as f32)
+            .collect();
+        Ok(samples)
+    }
+}
+
+/// What a connection collected for the transcription it is about to request.
+#[derive(Default)]
+struct Transcription {
+    language: Option<String>,
+    model: Option<String>,
+    format: Option<AudioFormat>,
+    /// Mono samples at `format.rate`.
+    audio: Vec<f32>,
+}
+
+async fn session(
+    stream: TcpStream,
+    models: &ModelRegistry,
+    language: Option<Language>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut transcription = Transcription::default();
+
+    while let Some(event) = read_event(&mut reader).await? {
+        tracing::debug!(kind = event.kind, "received an event");
+        match event.kind.as_str() {
+            "describe" => write_event(&mut writer, "info", info(models)).await?,
+            "ping" => write_event(&mut writer, "pong", Value::Object(event.data)).await?,
+            "transcribe" => {
+                let field = |name| {
+                    event
+                        .data
+                        .get(name)
+                        .and_then(Value::as_str)
+                        .map(str::to_string)
+                };
+                transcription.language = field("language");
+                transcription.model = field("name");
+            }
+            "audio-start" => {
+                transcription.format = Some(audio_format(event.data)?);
+                transcription.audio.clear();
+            }
+            "audio-chunk" => {
+                let format = audio_format(event.data)?;
+                if transcription
+                    .format
+                    .is_some_and(|started| started != format)
+                {
+                    bail!("audio format changed mid-stream");
+                }
+                transcription.format = Some(format);
+                transcription.audio.extend(format.decode(&event.payload)?);
+                let limit = u64::from(MAX_AUDIO_SECONDS) * u64::from(format.rate);
+                if transcription.audio.len() as u64 > limit {
+                    let message = format!("audio is longer than {MAX_AUDIO_SECONDS}s");
+                    write_error(&mut writer, &message, "audio-too-long").await?;
+                    bail!(message);
+                }
+            }
+            "audio-stop" => match transcribe(models, &mut transcription, language).await {
+                Ok(text) => {
+                    write_event(&mut writer, "transcript", json!({ "text": text })).await?;
+                }
+                Err(e) => {
+                    let message = format!("{e:#}");
+                    write_error(&mut writer, &message, "transcription-failed").await?;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn transcribe(
+    models: &ModelRegistry,
+    transcription: &mut Transcription,
+    default_language: Option<Language>,
+) -> Result<String> {
+    let rate = transcription
+        .format
+        .map_or(SAMPLE_RATE, |format| format.rate);
+    let samples = resample(&std::mem::take(&mut transcription.audio), rate);
+    let language = transcription
+        .language
+        .as_deref()
+        .map(str::parse::<Language>)
+        .transpose()
+        .map_err(|e| anyhow!(e))?
+        .or(default_language);
+
+    let pool = models.get(transcription.model.as_deref()).await?;
+    let options = TranscribeOptions {
+        language,
+        ..TranscribeOptions::default()
+    };
+    let started = Instant::now();
+    let transcript = pool
+        .run(move |whisper, state| whisper.transcribe_samples_with_state(state, &samples, &options))
+        .await
+        .map_err(|_| anyhow!("the transcription workers are busy or unavailable"))??;
+    tracing::info!(
+        audio = ?transcript.duration,
+        elapsed = ?started.elapsed(),
+        "transcribed"
+    );
+
+    Ok(transcript.as_text().trim().to_string())
+}
+
+/// Resamples mono audio to 16 kHz by linear interpolation.
+fn resample(samples: &[f32], rate: u32) -> Vec<f32> {
+    if rate == SAMPLE_RATE || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let step = f64::from(rate) / f64::from(SAMPLE_RATE);
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let resampled = (0..(samples.len() as f64 / step) as usize)
+        .map(|i| {
+            let position = i as f64 * step;
+            let index = position as usize;
+            let next = samples.get(index + 1).unwrap_or(&samples[index]);
+            let fraction = (position - index as f64) as f32;
+            samples[index].mul_add(1.0 - fraction, next * fraction)
+        })
+        .collect();
+    resampled
+}
+
+/// Describes the served models, each with the languages it understands.
+fn info(models: &ModelRegistry) -> Value {
+    let all_languages: Vec<&str> = Language::value_variants()
+        .iter()
+        .filter(|language| **language != Language::Auto)
+        .map(|language| (*language).into())
+        .collect();
+    let attribution = json!({
+        "name": "OpenAI",
+        "url": "https://github.com/openai/whisper",
+    });
+
+    let models: Vec<Value> = models
+        .list()
+        .into_iter()
+        .map(|(id, _)| {
+            let english_only = Size::from_str(&id, false).is_ok_and(Size::is_english_only);
+            json!({
+                "name": id,
+                "description": format!("Whisper {id}"),
+                "attribution": attribution,
+                "installed": true,
+                "version": null,
+                "languages": if english_only { vec!["en"] } else { all_languages.clone() },
+            })
+        })
+        .collect();
+
+    json!({
+        "asr": [{
+            "name": env!("CARGO_PKG_NAME"),
+            "description": "Whisper speech-to-text",
+            "attribution": attribution,
+            "installed": true,
+            "version": env!("CARGO_PKG_VERSION"),
+            "models": models,
+        }],
+    })
+}
+
+fn audio_format(data: Map<String, Value>) -> Result<AudioFormat> {
+    serde_json::from_value(Value::Object(data)).context("invalid audio format")
+}
+
+/// Reads the next event, `None` once the client closed the connection.
+async fn read_event<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Event>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_HEADER_LENGTH)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        bail!("event header is incomplete or longer than {MAX_HEADER_LENGTH} bytes");
+    }
+
+    let header: Header = serde_json::from_slice(&line).context("invalid event header")?;
+    if header.data_length > MAX_BLOCK_LENGTH || header.payload_length > MAX_BLOCK_LENGTH {
+        bail!("event is larger than {MAX_BLOCK_LENGTH} bytes");
+    }
+
+    let mut data = header.data.unwrap_or_default();
+    if header.data_length > 0 {
+        let mut block = vec![0; header.data_length];
+        reader.read_exact(&mut block).await?;
+        let extra: Map<String, Value> =
+            serde_json::from_slice(&block).context("invalid event data")?;
+        data.extend(extra);
+    }
+
+    let mut payload = vec![0; header.payload_length];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(Event {
+        kind: header.kind,
+        data,
+        payload,
+    }))
+}
+
+async fn write_event<W: AsyncWrite + Unpin>(writer: &mut W, kind: &str, data: Value) -> Result<()> {
+    let mut line = serde_json::to_vec(&json!({
+        "type": kind,
+        "data": data,
+        "version": PROTOCOL_VERSION,
+    }))?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn write_error<W: AsyncWrite + Unpin>(writer: &mut W, text: &str, code: &str) -> Result<()> {
+    write_event(writer, "error", json!({ "text": text, "code": code })).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_event() {
+        let mut stream: &[u8] = b"{\"type\":\"audio-chunk\",\"data\":{\"rate\":16000},\"data_length\":24,\"payload_length\":4}\n\
+            {\"width\":2,\"channels\":1}\x00\x40\x00\xc0\
+            {\"type\":\"audio-stop\"}\n";
+
+        let chunk = read_event(&mut stream).await.unwrap().unwrap();
+        assert_eq!(chunk.kind, "audio-chunk");
+        let format = audio_format(chunk.data).unwrap();
+        assert_eq!(
+            format,
+            AudioFormat {
+                rate: 16000,
+                width: 2,
+                channels: 1
+            }
+        );
+        assert_eq!(format.decode(&chunk.payload).unwrap(), [0.5, -0.5]);
+
+        let stop = read_event(&mut stream).await.unwrap().unwrap();
+        assert_eq!(stop.kind, "audio-stop");
+        assert!(stop.data.is_empty() && stop.payload.is_empty());
+        assert!(read_event(&mut stream).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_convert_audio() {
+        let stereo = AudioFormat {
+            rate: 32000,
+            width: 2,
+            channels: 2,
+        };
+        let samples = stereo.decode(&[0x00, 0x40, 0x00, 0x00]).unwrap();
+        assert_eq!(samples, [0.25]);
+
+        let resampled = resample(&[0.0, 0.5, 1.0, 0.5], 32000);
+        assert_eq!(resampled, [0.0, 1.0]);
+    }
+}
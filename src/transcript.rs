@@ -1,11 +1,16 @@
+use flate2::{write::ZlibEncoder, Compression};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{io::Write, time::Duration};
 
 use crate::utils::format_timestamp;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Transcript {
     pub processing_time: Duration,
+    /// Length of the transcribed audio.
+    pub duration: Duration,
+    /// Language code whisper decoded the audio as.
+    pub language: Option<String>,
     pub utterances: Vec<Utternace>,
     pub word_utterances: Option<Vec<Utternace>>,
 }
@@ -15,6 +20,31 @@ pub struct Utternace {
     pub start: i64,
     pub stop: i64,
     pub text: String,
+    /// Ids of the text tokens decoded for this fragment.
+    pub tokens: Vec<i32>,
+    /// Mean log probability of `tokens`.
+    pub avg_logprob: f32,
+}
+
+impl Utternace {
+    /// Ratio between the raw and zlib-compressed text, as reported by `OpenAI`.
+    /// High values point at repetitive (often hallucinated) output.
+    pub fn compression_ratio(&self) -> f32 {
+        let text = self.text.as_bytes();
+        if text.is_empty() {
+            return 0.0;
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        let compressed = encoder
+            .write_all(text)
+            .and_then(|()| encoder.finish())
+            .map_or(text.len(), |c| c.len());
+
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = text.len() as f32 / compressed as f32;
+        ratio
+    }
 }
 
 impl Transcript {
@@ -1,22 +1,24 @@
 #![allow(dead_code)]
+use anyhow::{anyhow, Context, Result};
 use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use num::integer::div_floor;
 use reqwest::Client;
-use std::{cmp::min, fs::File, io::Write, path::PathBuf};
+use std::{cmp::min, fs, fs::File, io::Write, path::PathBuf};
 
-pub async fn download_file(url: &str, path: &str) {
+/// Downloads `url` to `path`. The file only appears at `path` once complete, so an
+/// interrupted download is retried from scratch rather than mistaken for a finished one.
+pub async fn download_file(url: &str, path: &str) -> Result<()> {
     let res = Client::new()
         .get(url)
         .send()
         .await
-        .or(Err(format!("Failed to GET from '{}'", &url)))
-        .unwrap();
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("Failed to GET from '{url}'"))?;
 
     let total_size = res
         .content_length()
-        .ok_or(format!("Failed to get content length from '{}'", &url))
-        .unwrap();
+        .ok_or_else(|| anyhow!("Failed to get content length from '{url}'"))?;
 
     let pb = ProgressBar::new(total_size);
     pb.set_style(ProgressStyle::default_bar()
@@ -24,26 +26,33 @@ pub async fn download_file(url: &str, path: &str) {
         .progress_chars("#>-"));
     pb.set_message(format!("Downloading {url}",));
 
-    let mut file = File::create(path)
-        .or(Err(format!("Failed to create file '{path}'")))
-        .unwrap();
+    let partial = format!("{path}.part");
+    let mut file =
+        File::create(&partial).with_context(|| format!("Failed to create file '{partial}'"))?;
 
     let mut downloaded: u64 = 0;
     let mut stream = res.bytes_stream();
 
     while let Some(item) = stream.next().await {
-        let chunk = item.or(Err("Error while downloading file")).unwrap();
+        let chunk = item.context("Error while downloading file")?;
 
         file.write_all(&chunk)
-            .or(Err("Error while writing to file"))
-            .unwrap();
+            .context("Error while writing to file")?;
 
         let new = min(downloaded + (chunk.len() as u64), total_size);
         pb.set_position(new);
         downloaded = new;
     }
 
+    if downloaded < total_size {
+        return Err(anyhow!(
+            "Download of '{url}' ended after {downloaded} of {total_size} bytes"
+        ));
+    }
+    fs::rename(&partial, path).with_context(|| format!("Failed to move file to '{path}'"))?;
+
     pb.finish_with_message(format!("Downloaded {url} to {path}"));
+    Ok(())
 }
 
 pub fn format_timestamp(seconds: i64, always_include_hours: bool, decimal_marker: &str) -> String {
@@ -68,9 +77,8 @@ pub fn format_timestamp(seconds: i64, always_include_hours: bool, decimal_marker
     format!("{hours_marker}{minutes:02}:{seconds:02}{decimal_marker}{milliseconds:03}")
 }
 
-pub fn write_to(path: PathBuf, content: &String) {
-    File::create(path)
-        .unwrap()
-        .write_all(content.as_bytes())
-        .unwrap();
+pub fn write_to(path: PathBuf, content: &str) -> Result<()> {
+    File::create(&path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .with_context(|| format!("Failed to write '{}'", path.display()))
 }
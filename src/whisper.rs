@@ -1,15 +1,32 @@
-use std::{path::Path, time::Instant};
+use std::{
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Result};
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use whisper_rs::{
+    FullParams, SamplingStrategy, SegmentCallbackData, WhisperContext, WhisperContextParameters,
+    WhisperState,
+};
 
 use crate::{
+    cache::TranscriptCache,
     ffmpeg_decoder,
     model::Model,
     transcript::{Transcript, Utternace},
 };
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+/// Sample rate whisper expects its input audio in, that of decoded audio.
+pub const SAMPLE_RATE: u32 = 16_000;
+
+/// Languages written without spaces between words, where every token counts as a word.
+const UNSPACED_LANGUAGES: &[&str] = &["zh", "ja", "th", "lo", "km", "my", "bo"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 pub enum Language {
     #[clap(name = "auto")]
     Auto,
@@ -321,37 +338,102 @@ impl From<Language> for &str {
     }
 }
 
+impl FromStr for Language {
+    type Err = String;
+
+    /// Parses an ISO 639-1 code such as `en`, or `auto`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(s, true)
+            .map_err(|_| format!("unsupported language code `{s}`"))
+    }
+}
+
+/// Decoding options for a single transcription.
+#[derive(Clone, Debug, Default)]
+pub struct TranscribeOptions {
+    /// Translate the audio to English.
+    pub translate: bool,
+    /// Collect timestamps for every token in `Transcript::word_utterances`.
+    pub word_timestamps: bool,
+    /// Text to prime the decoder with, e.g. names or spellings it should follow.
+    pub prompt: Option<String>,
+    /// Language spoken in the audio. Falls back to the language the `Whisper` was created with.
+    pub language: Option<Language>,
+    /// Sampling temperature between 0 and 1. Leaves whisper's default when unset.
+    pub temperature: Option<f32>,
+}
+
+/// Failures of a transcription caused by its input, attached to the returned
+/// [`anyhow::Error`] so callers can tell them apart with `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscribeError {
+    /// The audio file could not be decoded.
+    InvalidAudio,
+    /// Whisper found no speech in the audio.
+    NoSpeech,
+}
+
+impl fmt::Display for TranscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAudio => write!(f, "failed to decode the audio"),
+            Self::NoSpeech => write!(f, "No segments found"),
+        }
+    }
+}
+
+impl std::error::Error for TranscribeError {}
+
 pub struct Whisper {
     ctx: WhisperContext,
     lang: Option<Language>,
+    /// Absolute path of the model file, part of the cache key.
+    model: String,
+    cache: Option<Arc<TranscriptCache>>,
 }
 
 impl Whisper {
     pub async fn new(model: Model, lang: Option<Language>) -> Self {
-        // fixme: download is not reliable
-        model.download().await;
+        model.download().await.expect("Failed to download model.");
 
-        Self {
-            lang,
-            ctx: WhisperContext::new_with_params(
-                model.get_path().to_str().unwrap(),
-                WhisperContextParameters::default(),
-            )
-            .expect("Failed to load model."),
-        }
+        Self::from_model_path(model.get_path(), lang).await
     }
 
     pub async fn from_model_path<P: AsRef<Path>>(model: P, lang: Option<Language>) -> Self {
+        let model = model.as_ref();
         Self {
             lang,
             ctx: WhisperContext::new_with_params(
-                model.as_ref().to_str().unwrap(),
+                model.to_str().unwrap(),
                 WhisperContextParameters::default(),
             )
             .expect("Failed to load model."),
+            model: fs::canonicalize(model)
+                .unwrap_or_else(|_| model.to_path_buf())
+                .display()
+                .to_string(),
+            cache: None,
         }
     }
 
+    /// Looks up file transcriptions in `cache` before running them, and stores their
+    /// results there.
+    #[must_use]
+    pub fn with_cache(mut self, cache: Arc<TranscriptCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Creates a decoding state for use with [`Whisper::transcribe_with_state`].
+    ///
+    /// States are independent of each other, so a single model can serve several
+    /// transcriptions at once by giving each one its own state.
+    pub fn create_state(&self) -> Result<WhisperState> {
+        self.ctx
+            .create_state()
+            .map_err(|e| anyhow!("failed to create state due to {:?}", e))
+    }
+
     pub fn transcribe<P: AsRef<Path>, F>(
         &self,
         audio: P,
@@ -363,30 +445,205 @@ impl Whisper {
     where
         F: FnMut(i32) + 'static,
     {
+        let mut state = self.create_state()?;
+        let options = TranscribeOptions {
+            translate,
+            word_timestamps,
+            prompt: prompt.map(ToString::to_string),
+            ..TranscribeOptions::default()
+        };
+        self.transcribe_with_state(&mut state, audio, &options, progress)
+    }
+
+    #[tracing::instrument(name = "transcribe", skip_all, fields(audio = %audio.as_ref().display()))]
+    pub fn transcribe_with_state<P: AsRef<Path>, F>(
+        &self,
+        state: &mut WhisperState,
+        audio: P,
+        options: &TranscribeOptions,
+        progress: F,
+    ) -> Result<Transcript>
+    where
+        F: FnMut(i32) + 'static,
+    {
+        let st = Instant::now();
+        let audio = Self::decode(audio)?;
+        self.transcribe_decoded(state, &audio, options, progress, st)
+    }
+
+    /// Decodes an audio file into the 16 kHz mono samples whisper works on. The path `-`
+    /// reads standard input.
+    pub fn decode<P: AsRef<Path>>(audio: P) -> Result<Vec<f32>> {
+        ffmpeg_decoder::read_file(audio).context(TranscribeError::InvalidAudio)
+    }
+
+    /// Like [`Whisper::transcribe_with_state`], for audio from [`Whisper::decode`], e.g.
+    /// when its length is needed before transcribing.
+    pub fn transcribe_decoded_with_state<F>(
+        &self,
+        state: &mut WhisperState,
+        samples: &[f32],
+        options: &TranscribeOptions,
+        progress: F,
+    ) -> Result<Transcript>
+    where
+        F: FnMut(i32) + 'static,
+    {
+        self.transcribe_decoded(state, samples, options, progress, Instant::now())
+    }
+
+    fn transcribe_decoded<F>(
+        &self,
+        state: &mut WhisperState,
+        audio: &[f32],
+        options: &TranscribeOptions,
+        progress: F,
+        st: Instant,
+    ) -> Result<Transcript>
+    where
+        F: FnMut(i32) + 'static,
+    {
+        let key = self.cache_key(audio, options);
+        if let Some(transcript) = self.cached(key.as_deref(), st) {
+            return Ok(transcript);
+        }
+
+        let mut params = self.params(options);
+        params.set_progress_callback_safe(progress);
+        let transcript =
+            Self::run(state, params, audio, options.word_timestamps, st).and_then(non_empty)?;
+        self.store(key.as_deref(), &transcript);
+        Ok(transcript)
+    }
+
+    /// Like [`Whisper::transcribe_with_state`], but hands every segment to `on_segment`
+    /// as soon as whisper has decoded it, instead of reporting progress.
+    #[tracing::instrument(name = "transcribe", skip_all, fields(audio = %audio.as_ref().display()))]
+    pub fn transcribe_streaming_with_state<P: AsRef<Path>, F>(
+        &self,
+        state: &mut WhisperState,
+        audio: P,
+        options: &TranscribeOptions,
+        mut on_segment: F,
+    ) -> Result<Transcript>
+    where
+        F: FnMut(SegmentCallbackData) + 'static,
+    {
+        let st = Instant::now();
+        let audio = Self::decode(audio)?;
+        let key = self.cache_key(&audio, options);
+        if let Some(transcript) = self.cached(key.as_deref(), st) {
+            // replay the stored segments, so callers can't tell the difference
+            for (segment, utterance) in transcript.utterances.iter().enumerate() {
+                on_segment(SegmentCallbackData {
+                    segment: i32::try_from(segment).unwrap_or(i32::MAX),
+                    start_timestamp: utterance.start,
+                    end_timestamp: utterance.stop,
+                    text: utterance.text.clone(),
+                });
+            }
+            return Ok(transcript);
+        }
+
+        let mut params = self.params(options);
+        params.set_segment_callback_safe(on_segment);
+        let transcript =
+            Self::run(state, params, &audio, options.word_timestamps, st).and_then(non_empty)?;
+        self.store(key.as_deref(), &transcript);
+        Ok(transcript)
+    }
+
+    /// Transcribes 16 kHz mono samples that are already in memory.
+    ///
+    /// Unlike the file based methods, audio without speech yields an empty transcript
+    /// rather than an error, as short live chunks are often silent.
+    #[tracing::instrument(level = "debug", name = "transcribe_samples", skip_all)]
+    pub fn transcribe_samples_with_state(
+        &self,
+        state: &mut WhisperState,
+        samples: &[f32],
+        options: &TranscribeOptions,
+    ) -> Result<Transcript> {
         let st = Instant::now();
-        let mut state = self.ctx.create_state().expect("failed to create state");
+        let params = self.params(options);
+        Self::run(state, params, samples, options.word_timestamps, st)
+    }
+
+    fn cache_key(&self, audio: &[f32], options: &TranscribeOptions) -> Option<String> {
+        self.cache.as_ref().map(|_| {
+            TranscriptCache::key(audio, &self.model, options.language.or(self.lang), options)
+        })
+    }
+
+    /// The cached transcript for `key`, timed as if it had just been made.
+    fn cached(&self, key: Option<&str>, st: Instant) -> Option<Transcript> {
+        let key = key?;
+        let mut transcript = self.cache.as_ref()?.get(key)?;
+        tracing::debug!(key, "found in the cache");
+        transcript.processing_time = st.elapsed();
+        Some(transcript)
+    }
+
+    fn store(&self, key: Option<&str>, transcript: &Transcript) {
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            if let Err(e) = cache.put(key, transcript) {
+                tracing::warn!("failed to cache the transcript: {e:#}");
+            }
+        }
+    }
+
+    fn params<'a, 'b>(&self, options: &TranscribeOptions) -> FullParams<'a, 'b> {
         let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
-        if let Some(prompt) = prompt {
+        if let Some(prompt) = &options.prompt {
             params.set_initial_prompt(prompt);
         }
-        params.set_translate(translate);
+        if let Some(temperature) = options.temperature {
+            params.set_temperature(temperature);
+        }
+        params.set_translate(options.translate);
         params.set_print_special(false);
         params.set_print_progress(false);
         params.set_print_realtime(false);
         params.set_print_timestamps(false);
-        params.set_token_timestamps(word_timestamps);
-        // fixme: Process exits when language detection is enabled https://github.com/tazz4843/whisper-rs/issues/103
-        // params.set_language(self.lang.map(Into::into));
-        params.set_progress_callback_safe(progress);
+        params.set_token_timestamps(options.word_timestamps);
+        // whisper.cpp assumes English unless told otherwise, `auto` detects the language
+        params.set_language(Some(
+            options
+                .language
+                .or(self.lang)
+                .unwrap_or(Language::Auto)
+                .into(),
+        ));
+
+        params
+    }
 
-        let audio = ffmpeg_decoder::read_file(audio)?;
+    fn run(
+        state: &mut WhisperState,
+        params: FullParams,
+        audio: &[f32],
+        word_timestamps: bool,
+        st: Instant,
+    ) -> Result<Transcript> {
+        #[allow(clippy::cast_precision_loss)]
+        let duration = Duration::from_secs_f64(audio.len() as f64 / f64::from(SAMPLE_RATE));
 
-        state.full(params, &audio).expect("failed to transcribe");
+        state
+            .full(params, audio)
+            .map_err(|e| anyhow!("failed to transcribe due to {:?}", e))?;
 
-        let num_segments = state.full_n_segments().expect("failed to get segments");
-        if num_segments == 0 {
-            return Err(anyhow!("No segments found"));
-        };
+        let num_segments = state
+            .full_n_segments()
+            .map_err(|e| anyhow!("failed to get segments due to {:?}", e))?;
+
+        let language = state
+            .full_lang_id_from_state()
+            .ok()
+            .and_then(whisper_rs::get_lang_str)
+            .map(ToString::to_string);
+        let unspaced = language
+            .as_deref()
+            .is_some_and(|language| UNSPACED_LANGUAGES.contains(&language));
 
         let mut words = Vec::new();
         let mut utterances = Vec::new();
@@ -401,16 +658,12 @@ impl Whisper {
                 .full_get_segment_t1(s)
                 .map_err(|e| anyhow!("failed to get segment due to {:?}", e))?;
 
-            utterances.push(Utternace { text, start, stop });
-
-            if !word_timestamps {
-                continue;
-            }
-
             let num_tokens = state
                 .full_n_tokens(s)
                 .map_err(|e| anyhow!("failed to get segment due to {:?}", e))?;
 
+            let mut tokens = Vec::new();
+            let mut logprob_sum = 0.0;
             for t in 0..num_tokens {
                 let text = state
                     .full_get_token_text(s, t)
@@ -423,18 +676,125 @@ impl Whisper {
                     continue;
                 }
 
-                words.push(Utternace {
-                    text,
-                    start: token_data.t0,
-                    stop: token_data.t1,
-                });
+                tokens.push(token_data.id);
+                logprob_sum += token_data.plog;
+
+                if word_timestamps {
+                    push_token(
+                        &mut words,
+                        Utternace {
+                            text,
+                            start: token_data.t0,
+                            stop: token_data.t1,
+                            tokens: vec![token_data.id],
+                            avg_logprob: token_data.plog,
+                        },
+                        tokens.len() == 1,
+                        unspaced,
+                    );
+                }
             }
+
+            #[allow(clippy::cast_precision_loss)]
+            let avg_logprob = if tokens.is_empty() {
+                0.0
+            } else {
+                logprob_sum / tokens.len() as f32
+            };
+
+            utterances.push(Utternace {
+                text,
+                start,
+                stop,
+                tokens,
+                avg_logprob,
+            });
         }
 
+        tracing::debug!(
+            segments = num_segments,
+            language = language.as_deref(),
+            elapsed = ?st.elapsed(),
+            "decoded {duration:?} of audio"
+        );
         Ok(Transcript {
             utterances,
+            language,
+            duration,
             processing_time: Instant::now().duration_since(st),
             word_utterances: if word_timestamps { Some(words) } else { None },
         })
     }
 }
+
+/// Adds a token to `words`. Tokens are often pieces of words (" Hel", "lo"), so a new
+/// word only starts at a leading space or at the start of a segment, otherwise the
+/// token extends the last word. In `unspaced` languages every token is a word.
+fn push_token(words: &mut Vec<Utternace>, token: Utternace, starts_segment: bool, unspaced: bool) {
+    match words.last_mut() {
+        Some(word) if !starts_segment && !unspaced && !token.text.starts_with(' ') => {
+            #[allow(clippy::cast_precision_loss)]
+            let count = word.tokens.len() as f32;
+            word.avg_logprob = word.avg_logprob.mul_add(count, token.avg_logprob) / (count + 1.0);
+            word.text.push_str(&token.text);
+            word.stop = token.stop;
+            word.tokens.extend(token.tokens);
+        }
+        _ => words.push(token),
+    }
+}
+
+fn non_empty(transcript: Transcript) -> Result<Transcript> {
+    if transcript.utterances.is_empty() {
+        return Err(TranscribeError::NoSpeech.into());
+    }
+
+    Ok(transcript)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(text: &str, start: i64, stop: i64) -> Utternace {
+        Utternace {
+            text: text.to_string(),
+            start,
+            stop,
+            tokens: vec![1],
+            avg_logprob: -0.5,
+        }
+    }
+
+    #[test]
+    fn test_push_token() {
+        let mut words = Vec::new();
+        push_token(&mut words, token(" Hel", 0, 20), true, false);
+        push_token(&mut words, token("lo", 20, 35), false, false);
+        push_token(&mut words, token(" world", 35, 60), false, false);
+        push_token(&mut words, token("Again", 100, 120), true, false);
+
+        let words: Vec<_> = words
+            .iter()
+            .map(|word| (word.text.as_str(), word.start, word.stop, word.tokens.len()))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                (" Hello", 0, 35, 2),
+                (" world", 35, 60, 1),
+                ("Again", 100, 120, 1)
+            ]
+        );
+
+        // without spaces, as in Chinese, every token is a word of its own
+        let mut words = Vec::new();
+        push_token(&mut words, token("你好", 0, 30), true, true);
+        push_token(&mut words, token("世界", 30, 55), false, true);
+        let words: Vec<_> = words
+            .iter()
+            .map(|word| (word.text.as_str(), word.start, word.stop))
+            .collect();
+        assert_eq!(words, [("你好", 0, 30), ("世界", 30, 55)]);
+    }
+}
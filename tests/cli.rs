@@ -0,0 +1,80 @@
+use std::fs::{self, File};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use whisper_cli::{
+    Language, Size, TranscribeOptions, Transcript, TranscriptCache, Utternace, Whisper,
+};
+
+/// Piping audio through `transcribe -` leaves nothing but the transcript on stdout, so it
+/// can be redirected to a file. The transcript is answered from a cache seeded here, which
+/// keeps the test independent of what the model makes of the audio.
+#[test]
+#[cfg(target_os = "linux")]
+#[ignore = "needs the tiny.en model, download it with `whisper models download tiny.en`"]
+fn test_stdout_holds_only_the_transcript() {
+    let home = tempfile::tempdir().unwrap();
+    let models = home.path().join("whisper/models");
+    fs::create_dir_all(&models).unwrap();
+    let model = models.join("tiny.en.bin");
+    std::os::unix::fs::symlink(Size::TinyEnglish.get_path(), &model).unwrap();
+
+    let audio = home.path().join("tone.wav");
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 16_000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&audio, spec).unwrap();
+    for i in 0..16_000 {
+        let t = f64::from(i) / 16_000.0;
+        #[allow(clippy::cast_possible_truncation)]
+        writer
+            .write_sample(((t * 440.0 * std::f64::consts::TAU).sin() * 8_000.0) as i16)
+            .unwrap();
+    }
+    writer.finalize().unwrap();
+
+    let transcript = Transcript {
+        processing_time: Duration::ZERO,
+        duration: Duration::from_secs(1),
+        language: Some("en".to_string()),
+        utterances: vec![Utternace {
+            start: 0,
+            stop: 100,
+            text: " A single tone.".to_string(),
+            tokens: vec![],
+            avg_logprob: 0.0,
+        }],
+        word_utterances: None,
+    };
+    // the CLI keys the cache like this for `tiny.en` without further options
+    let key = TranscriptCache::key(
+        &Whisper::decode(&audio).unwrap(),
+        &fs::canonicalize(&model).unwrap().display().to_string(),
+        Some(Language::English),
+        &TranscribeOptions::default(),
+    );
+    TranscriptCache::new(
+        home.path().join("whisper/transcripts"),
+        TranscriptCache::DEFAULT_MAX_SIZE,
+        TranscriptCache::DEFAULT_MAX_AGE,
+    )
+    .put(&key, &transcript)
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_whisper"))
+        .args(["transcribe", "-m", "tiny.en", "--stdout-format", "srt", "-"])
+        .env("XDG_CACHE_HOME", home.path())
+        .stdin(File::open(&audio).unwrap())
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}\n", transcript.as_srt())
+    );
+}